@@ -1,944 +1,5427 @@
-use std::collections::HashMap;
-use std::env;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::Write;
 use std::iter::once;
+use std::net::SocketAddr;
 use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
+use std::process;
 use std::vec::Vec;
 use std::string::String;
 use std::sync::Arc;
 use std::time;
 
-use argon2;
 use axum::{
-    BoxError,
+    async_trait,
     body::Body,
-    error_handling::HandleErrorLayer,
     extract::{
+        ConnectInfo,
         DefaultBodyLimit,
+        FromRequest,
+        FromRequestParts,
+        MatchedPath,
         Multipart,
         Path,
+        Query,
         State,
     },
     http::{
         header,
+        HeaderMap,
+        HeaderName,
         HeaderValue,
         Method,
+        request::Parts,
         Request,
         StatusCode,
     },
+    Extension,
     Json,
     middleware::{self, Next},
-    response::{IntoResponse, Response},
+    response::{
+        IntoResponse,
+        Response,
+        sse::{Event, KeepAlive, Sse},
+    },
     Router,
-    routing::{get, post},
+    routing::{get, post, MethodRouter},
 };
-use chrono::{DateTime, Duration, Utc, FixedOffset};
+use axum_server::tls_rustls::RustlsConfig;
+use base64::Engine;
+use metrics_exporter_prometheus::PrometheusBuilder;
+use bytes::Bytes;
+use chrono::{DateTime, Utc, FixedOffset};
 use chrono::offset::TimeZone;
 use dotenv::dotenv;
-use git2::{Index, IndexEntry, IndexTime, Repository, Oid};
+use futures::StreamExt;
+use git2::{DiffOptions, Index, IndexEntry, IndexTime, Repository, Oid};
 use jsonwebtoken as jwt;
-use mime_guess;
 use tower::ServiceBuilder;
 use tower_http::{
-    cors::CorsLayer,
+    compression::CompressionLayer,
+    cors::{Any, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
     sensitive_headers::SetSensitiveHeadersLayer,
-    trace::TraceLayer,
+    trace::{DefaultOnResponse, TraceLayer},
+    LatencyUnit,
 };
-use tracing::debug;
+use tracing::{debug, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use uuid::Uuid;
 
 use models::*;
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
-        .with(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
-
     dotenv().ok();
 
-    let repo = {
-        let git_dir = env::var("MORIED_GIT_DIR").unwrap();
-        match Repository::open(git_dir) {
-            Ok(repo) => repo,
-            Err(e) => panic!("failed to open: {}", e),
+    let registry = tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::from_default_env());
+    match std::env::var("MORIED_LOG_FORMAT").as_deref() {
+        Ok("pretty") | Err(_) => registry.with(tracing_subscriber::fmt::layer()).init(),
+        // One JSON object per line for log aggregators
+        Ok("json") => registry.with(tracing_subscriber::fmt::layer().json().flatten_event(true)).init(),
+        Ok(format) => {
+            eprintln!("moried: MORIED_LOG_FORMAT must be either pretty or json, but got {:?}", format);
+            process::exit(1);
+        },
+    }
+
+    let config = match Config::from_env() {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("moried: invalid configuration:");
+            for e in errors {
+                eprintln!("  {}", e);
+            }
+            process::exit(1);
+        },
+    };
+    debug!("token TTL: {:?}", config.token_ttl);
+
+    let repo = match Repository::open(&config.git_dir) {
+        Ok(repo) => repo,
+        Err(e) => {
+            eprintln!("moried: failed to open {}: {}", config.git_dir.display(), e);
+            process::exit(1);
+        },
+    };
+    if let Some(branch) = &config.branch {
+        // A freshly initialized repository gets the branch with the first commit
+        if let Err(e) = repo.find_branch(branch, git2::BranchType::Local) {
+            if !repo.head().is_err_and(|e| e.code() == git2::ErrorCode::UnbornBranch) {
+                eprintln!("moried: branch {} not found in {}: {}", branch, config.git_dir.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+    for remote in config.push_remote.iter().chain(&config.pull_remote) {
+        if let Err(e) = repo.find_remote(remote) {
+            eprintln!("moried: remote {} not found in {}: {}", remote, config.git_dir.display(), e);
+            process::exit(1);
         }
+    }
+    let metrics = if config.metrics_enabled {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets(&[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0])
+            .unwrap()
+            .install_recorder();
+        match recorder {
+            Ok(handle) => Some(handle),
+            Err(e) => {
+                eprintln!("moried: failed to set up metrics: {}", e);
+                process::exit(1);
+            },
+        }
+    }
+    else {
+        None
     };
-    let state = Arc::new(models::AppState::new(repo));
+    let state = Arc::new(models::AppState::new(repo, config, metrics));
+
+    if let Some(remote) = state.config.pull_remote.clone() {
+        tokio::spawn(pull_periodically(Arc::clone(&state), remote));
+    }
 
-    let addr = env::var("MORIED_LISTEN").unwrap();
+    let addr = state.config.listen.clone();
     debug!("{:?}", addr);
 
+    let app = router(Arc::clone(&state));
+
+    // Streams such as /events end on this as well, since they would keep the server waiting otherwise
+    tokio::spawn({
+        let shutdown = state.shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.cancel();
+        }
+    });
+
+    if let Some((cert, key)) = &state.config.tls {
+        let tls_config = match RustlsConfig::from_pem_file(cert, key).await {
+            Ok(tls_config) => tls_config,
+            Err(e) => {
+                eprintln!("moried: failed to load the TLS certificate or key: {}", e);
+                process::exit(1);
+            },
+        };
+        let addr = match tokio::net::lookup_host(&addr).await.ok().and_then(|mut addrs| addrs.next()) {
+            Some(addr) => addr,
+            None => {
+                eprintln!("moried: failed to resolve {}", addr);
+                process::exit(1);
+            },
+        };
+        let handle = axum_server::Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+            let shutdown = state.shutdown.clone();
+            async move {
+                shutdown.cancelled().await;
+                handle.graceful_shutdown(Some(SHUTDOWN_TIMEOUT));
+            }
+        });
+        axum_server::bind_rustls(addr, tls_config)
+            .handle(handle)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .await
+            .unwrap();
+    }
+    else {
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(state.shutdown.clone().cancelled_owned());
+        // Give up on connections which are still open after a while
+        let timeout = async {
+            state.shutdown.cancelled().await;
+            tokio::time::sleep(SHUTDOWN_TIMEOUT).await;
+        };
+        tokio::select! {
+            served = server => served.unwrap(),
+            _ = timeout => warn!("closed connections still open after {:?}", SHUTDOWN_TIMEOUT),
+        }
+    }
+
+    // Make sure no commit is in progress
+    state.repo.wait_for_writers().await;
+}
+
+/// Builds the whole API, nested under MORIED_ROOT_PATH.
+fn router(state: Arc<AppState>) -> Router {
     let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
-        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
-        .allow_origin(env::var("MORIED_ORIGIN_ALLOWED").unwrap().parse::<HeaderValue>().unwrap())
-        .allow_credentials(true);
+        .allow_methods([Method::GET, Method::HEAD, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE, header::RANGE, HeaderName::from_static("x-upload-dir")])
+        .expose_headers([header::ETAG, header::LAST_MODIFIED, header::CONTENT_RANGE, header::ACCEPT_RANGES, HeaderName::from_static("x-request-id"), HeaderName::from_static("x-note-metadata")]);
+    let cors = match &state.config.cors_origins {
+        Some(origins) => cors
+            .allow_origin(origins.clone())
+            .allow_credentials(true),
+        // Credentials are not allowed together with a wildcard origin
+        None => cors.allow_origin(Any),
+    };
 
+    // Authenticate only requests with allowed methods, so that the others are told 405 Method Not Allowed rather than 401
+    let auth_layer = middleware::from_fn_with_state(state.clone(), auth);
+    let protect = |methods: MethodRouter<Arc<AppState>>| methods.route_layer(auth_layer.clone());
+    // Only notes and lists, leaving files as they are since many are compressed already
+    let compression = CompressionLayer::new()
+        .gzip(state.config.compression)
+        .deflate(state.config.compression);
     let protected_api = Router::new()
-        .route("/notes", get(get_notes))
-        .route("/notes/*path", get(get_notes_path).put(put_notes_path).delete(delete_notes_path))
-        .route("/files", post(post_files).layer(DefaultBodyLimit::max(16 * 1024 * 1024)))
-        .route("/files/*path", get(get_files_path))
-        .with_state(state)
-        .route_layer(middleware::from_fn(auth));
+        .route("/notes", protect(get(get_notes).layer(compression.clone()).put(put_notes)))
+        .route("/notes-bulk-delete", protect(post(post_notes_bulk_delete)))
+        .route("/notes/*path", protect(get(get_notes_path).layer(compression.clone()).head(head_notes_path).put(put_notes_path).post(post_notes_path).delete(delete_notes_path)))
+        // Leave some room for multipart boundaries and headers
+        .route("/files", protect(get(get_files).merge(post(post_files).layer(DefaultBodyLimit::max(state.config.max_upload_bytes + 64 * 1024)))))
+        .route("/files/*path", protect(get(get_files_path).delete(delete_files_path)))
+        .route("/tree", protect(get(get_tree)))
+        .route("/tree/", protect(get(get_tree)))
+        .route("/tree/*path", protect(get(get_tree_path)))
+        .route("/history/*path", protect(get(get_history_path)))
+        .route("/log", protect(get(get_log)))
+        .route("/search", protect(get(get_search).layer(compression)))
+        .route("/feed", protect(get(get_feed)))
+        .route("/export", protect(get(get_export)))
+        .route("/import", protect(post(post_import).layer(DefaultBodyLimit::max(state.config.max_upload_bytes + 64 * 1024))))
+        .route("/events", protect(get(get_events)))
+        .route("/git/blob/:id", protect(get(get_git_blob)))
+        .route("/git/commit/:id", protect(get(get_git_commit)))
+        .route("/tags", protect(get(get_tags).post(post_tags)))
+        .route("/tags/notes", protect(get(get_note_tags)))
+        .route("/logout", protect(post(post_logout)))
+        .route("/refresh", protect(post(post_refresh)))
+        .route("/password", protect(post(post_password)))
+        .route("/admin/cache/clear", protect(post(post_admin_cache_clear)));
+    // Syncing needs a remote to fetch from
+    let protected_api = if state.config.sync_remote().is_some() {
+        protected_api.route("/sync", protect(post(post_sync)))
+    }
+    else {
+        protected_api
+    };
+    let protected_api = protected_api.with_state(state.clone());
+    // Login attempts are limited per client by post_login
     let login_api = Router::new()
         .route("/login", post(post_login))
-        .route_layer(
-            ServiceBuilder::new()
-                .layer(HandleErrorLayer::new(|_: BoxError| async {
-                    // Too many requests
-                    StatusCode::SERVICE_UNAVAILABLE
-                }))
-                .load_shed()
-                .buffer(1)  // Required to make it Clone.
-                .rate_limit(1, time::Duration::from_secs(3))
-        );
+        .with_state(state.clone());
+    let public_api = Router::new()
+        .route("/health", get(get_health));
+    let public_api = if state.metrics.is_some() {
+        public_api.route("/metrics", get(get_metrics))
+    }
+    else {
+        public_api
+    };
+    let public_api = public_api.with_state(state.clone());
     let api = Router::new()
         .merge(protected_api)
         .merge(login_api)
+        .merge(public_api)
+        .fallback(fallback)
+        .route_layer(middleware::from_fn(track_metrics))
+        .layer(middleware::from_fn(method_not_allowed))
         .layer(
             ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
+                // Every log line of a request carries its ID, which is echoed back as X-Request-Id
+                .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+                .layer(TraceLayer::new_for_http().make_span_with(|request: &Request<Body>| {
+                    let request_id = request.headers()
+                        .get("x-request-id")
+                        .and_then(|id| id.to_str().ok())
+                        .unwrap_or_default();
+                    tracing::info_span!("request", id = %request_id, method = %request.method(), uri = %request.uri())
+                })
+                // Log the status and the duration of every request, also in release builds
+                .on_response(DefaultOnResponse::new().level(tracing::Level::INFO).latency_unit(LatencyUnit::Millis)))
+                .layer(PropagateRequestIdLayer::x_request_id())
                 .layer(SetSensitiveHeadersLayer::new(once(header::AUTHORIZATION)))
                 .layer(cors)
         );
 
-    let app = {
-        let root_path = env::var("MORIED_ROOT_PATH").unwrap();
-        assert!(root_path.starts_with('/'), "MORIED_ROOT_PATH must start with '/'");
-        assert!(root_path.ends_with('/'), "MORIED_ROOT_PATH must end with '/'");
+    let root_path = &state.config.root_path;
+    if root_path == "/" {
+        api
+    }
+    else {
+        Router::new().nest(root_path, api)
+    }
+}
 
-        if root_path == "/" {
-            api
-        }
-        else {
-            Router::new().nest(&root_path, api)
-        }
+/// How long to wait for open connections to close on shutdown.
+const SHUTDOWN_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Waits for Ctrl-C or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to listen for Ctrl-C");
+    };
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    tokio::select! {
+        _ = ctrl_c => (),
+        _ = terminate => (),
+    }
+    info!("shutting down");
+}
+
+/// Records the count and the duration of requests per route.
+async fn track_metrics(req: Request<Body>, next: Next) -> Response {
+    let method = req.method().to_string();
+    let route = match req.extensions().get::<MatchedPath>() {
+        Some(matched_path) => matched_path.as_str().to_owned(),
+        None => req.uri().path().to_owned(),
     };
 
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app.into_make_service())
-        .await
-        .unwrap();
+    let start = time::Instant::now();
+    let res = next.run(req).await;
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let status = res.status().as_u16().to_string();
+    metrics::counter!("moried_http_requests_total", "method" => method.clone(), "route" => route.clone(), "status" => status).increment(1);
+    metrics::histogram!("moried_http_request_duration_seconds", "method" => method, "route" => route).record(elapsed);
+
+    res
+}
+
+async fn get_metrics(
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    match &state.metrics {
+        Some(handle) => ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], handle.render()).into_response(),
+        None => ApiError::not_found("metrics are disabled").into_response(),
+    }
+}
+
+/// Replies to requests for unknown endpoints with a JSON error, like any other error.
+async fn fallback(method: Method, uri: axum::http::Uri) -> ApiError {
+    ApiError::not_found(format!("no such endpoint: {} {}", method, uri.path()))
+}
+
+/// Replies to requests with methods not allowed on known endpoints with a JSON error, keeping the `Allow` header.
+async fn method_not_allowed(req: Request<Body>, next: Next) -> Response {
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    let res = next.run(req).await;
+    if res.status() != StatusCode::METHOD_NOT_ALLOWED {
+        return res;
+    }
+
+    let mut error = ApiError::new(StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed", format!("{} is not allowed on {}", method, path)).into_response();
+    if let Some(allow) = res.headers().get(header::ALLOW) {
+        error.headers_mut().insert(header::ALLOW, allow.clone());
+    }
+    error
 }
 
-async fn auth(req: Request<Body>, next: Next) -> Result<Response, StatusCode> {
-    let auth_header = req
+async fn auth(
+    State(state): State<Arc<AppState>>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let claims = req
         .headers()
         .get(header::AUTHORIZATION)
-        .and_then(|value| value.to_str().ok());
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| decode_token(&state.config.decoding_key, value));
 
-    match auth_header {
-        Some(auth_header) if token_is_valid(auth_header) => {
+    match claims {
+        Some(claims) if state.revoked_tokens.lock().await.contains_key(&claims.jti) => {
+            debug!("token has been revoked");
+            Err(ApiError::unauthorized("token has been revoked"))
+        },
+        Some(claims) => {
+            // Make the claims available to handlers
+            req.extensions_mut().insert(claims);
             Ok(next.run(req).await)
         },
-        _ => Err(StatusCode::UNAUTHORIZED),
+        None => Err(ApiError::unauthorized("missing or invalid token")),
     }
 }
 
-fn token_is_valid(header_value: &str) -> bool {
-    let token = header_value.split_whitespace().nth(1).unwrap();
+fn decode_token(key: &jwt::DecodingKey, header_value: &str) -> Option<Claims> {
+    let token = header_value.split_whitespace().nth(1)?;
 
-    let secret = env::var("MORIED_SECRET").unwrap();
-    match jwt::decode::<Claims>(&token, &jwt::DecodingKey::from_secret(secret.as_ref()), &jwt::Validation::default()) {
-        Ok(_) => {
+    match jwt::decode::<Claims>(token, key, &jwt::Validation::default()) {
+        // Tokens without an ID could not be revoked one by one
+        Ok(data) if data.claims.jti.is_empty() => {
+            debug!("token has no ID");
+            None
+        },
+        Ok(data) => {
             debug!("authorized");
-            true
+            Some(data.claims)
         },
         Err(e) => {
             debug!("failed to decode token: {:?}", e);
-            false
+            None
         },
     }
 }
 
-async fn post_login(
-    Json(login): Json<Login>,
-) -> Response {
-    debug!("post_login");
-    let user_name = env::var("MORIED_USER_NAME").unwrap();
-    let user_email = env::var("MORIED_USER_EMAIL").unwrap();
-    let user_hash = env::var("MORIED_USER_HASH").unwrap();
-    let matches = user_name == login.user && argon2::verify_encoded(&user_hash, login.password.as_ref()).unwrap();
-
-    if matches {
-        let secret = env::var("MORIED_SECRET").unwrap();
-        let duration = env::var("MORIED_SESSION_DURATION").map_or(Duration::hours(6), |v| {
-            Duration::minutes(v.parse::<i64>().expect("Session duration in minutes represented as integer value is expected"))
-        });
-        println!("{:?}", duration);
-        let now: DateTime<Utc> = Utc::now();
-        let my_claims = Claims {
-            sub: login.user.to_owned(),
-            exp: (now + duration).timestamp() as usize,
-            email: user_email,
-        };
-        let token = jwt::encode(
-            &jwt::Header::default(),
-            &my_claims,
-            &jwt::EncodingKey::from_secret(secret.as_ref())
-        ).unwrap();
-        token.into_response()
-    }
-    else {
-        StatusCode::UNAUTHORIZED.into_response()
+/// Returns the signature of the logged-in user, falling back to the one configured in the repository.
+fn signature_for(repo: &Repository, claims: Option<&Claims>) -> Result<git2::Signature<'static>, git2::Error> {
+    match claims {
+        Some(claims) => git2::Signature::now(&claims.sub, &claims.email),
+        None => repo.signature(),
     }
 }
 
-async fn get_notes(
+async fn get_health(
     State(state): State<Arc<AppState>>,
-) -> Json<Vec<ListEntry>> {
-    debug!("get_notes");
-
-    // Check if a cache exists
-    let repo = state.repo.lock().await;
-    let mut cached_entries = state.cached_entries.lock().await;
-    match cached_entries.get(&repo) {
-        Cache::Valid(entries) => {
-            // Return the cache
-            Json(entries.clone())
-        },
-        Cache::Invalid(last_commit_id, old_entries) => {
-            use git2::Delta;
-
-            // Iterate over recent commit history to collect operations on files
-            let mut latest_ops: HashMap<PathBuf, (Delta, DateTime<FixedOffset>, Oid)> = HashMap::new();
-            let mut revwalk = repo.revwalk().unwrap();
-            revwalk.set_sorting(git2::Sort::TOPOLOGICAL).unwrap();
-            revwalk.push_range(&format!("{}..HEAD", last_commit_id)).unwrap();
-            for oid in revwalk {
-                let oid = oid.unwrap();
-                let commit = repo.find_commit(oid).unwrap();
-                debug!("{:?}", commit);
-
-                let time = {
-                    let t = commit.time();
-                    let tz = FixedOffset::east_opt(t.offset_minutes() * 60).unwrap();
-                    tz.timestamp_opt(t.seconds(), 0).unwrap()
-                };
-
-                let tree = commit.tree().unwrap();
-                for parent in commit.parents() {
-                    let parent_tree = parent.tree().unwrap();
-                    let diff = repo.diff_tree_to_tree(Some(&parent_tree), Some(&tree), None).unwrap();
-                    for delta in diff.deltas() {
-                        match delta.status() {
-                            Delta::Added | Delta::Modified => {
-                                let file = delta.new_file();
-                                let path = file.path().unwrap().to_owned();
-                                latest_ops.entry(path).or_insert((
-                                    delta.status(),
-                                    time,
-                                    file.id(),
-                                ));
-                            },
-                            Delta::Deleted => {
-                                let file = delta.old_file();
-                                let path = file.path().unwrap().to_owned();
-                                latest_ops.entry(path).or_insert((
-                                    delta.status(),
-                                    time,
-                                    file.id(),
-                                ));
-                            },
-                            _ => (),
-                        }
-                    }
-                }
-            }
-
-            // Update existing entries
-            let mut entries: Vec<ListEntry> = Vec::with_capacity(old_entries.len());
-            for entry in old_entries {
-                let mut found = false;
-                match latest_ops.get(&entry.path) {
-                    None => {
-                        // Entry is untouched
-                        entries.push(entry.clone());
-                    },
-                    Some(&(op, time, blob_id)) => {
-                        // Entry is modified or deleted
-                        found = true;
-                        match op {
-                            Delta::Added | Delta::Modified => {
-                                // Get the file size
-                                let blob = repo.find_blob(blob_id).unwrap();
-                                let size = blob.size();
-                                // Extract metadata
-                                let (metadata, title) = extract_metadata(blob.content());
-                                // Add an entry
-                                entries.push(ListEntry {
-                                    path: entry.path.to_owned(),
-                                    size: size,
-                                    mime_type: entry.mime_type.to_owned(),
-                                    metadata: metadata,
-                                    title: title,
-                                    time: time,
-                                });
-                            },
-                            Delta::Deleted => {
-                                // Ignore the entry
-                            },
-                            _ => unreachable!(),
-                        }
-                    },
-                }
-                if found {
-                    latest_ops.remove(&entry.path);
-                }
-            }
-
-            // Add newly created entries
-            for (path, (op, time, blob_id)) in latest_ops {
-                match op {
-                    Delta::Added | Delta::Modified => {
-                        // Guess the mime type
-                        let guess = mime_guess::from_path(&path);
-                        let mime_type = if let Some(mime) = guess.first() {
-                            mime.as_ref().parse().unwrap()
-                        }
-                        else {
-                            "application/octet-stream".to_string()
-                        };
-                        // Get the file size
-                        let blob = repo.find_blob(blob_id).unwrap();
-                        let size = blob.size();
-                        // Extract metadata
-                        let (metadata, title) = extract_metadata(blob.content());
-                        // Add an entry
-                        entries.push(ListEntry {
-                            path: path,
-                            size: size,
-                            mime_type: mime_type,
-                            metadata: metadata,
-                            title: title,
-                            time: time,
-                        });
-                    },
-                    Delta::Deleted => {
-                        // Ignore the entry
-                    },
-                    _ => unreachable!(),
-                }
-            }
+) -> Response {
+    debug!("get_health");
 
-            // Find the head commit
-            let head = repo.head().unwrap();
-            let head_commit = head.peel_to_commit().unwrap();
+    let head = match state.repo.read().await {
+        Ok(repo) if repo.is_unborn() => Ok(None),
+        Ok(repo) => repo.head_commit().map(|commit| Some(commit.id())),
+        Err(GitError(message)) => return ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "unavailable", message).into_response(),
+    };
+    match head {
+        Ok(head) => Json(Health {
+            status: "ok",
+            head: head.map(|head| head.to_string()),
+        }).into_response(),
+        Err(e) => ApiError::new(StatusCode::SERVICE_UNAVAILABLE, "unavailable", format!("failed to resolve HEAD: {}", e.message())).into_response(),
+    }
+}
 
-            // Save to a cache file
-            let mut cache_file = File::create("cache.msgpack").unwrap();
-            rmp_serde::encode::write(&mut cache_file, &models::EntriesCache {
-                commit_id: head_commit.id().to_string(),
-                entries: entries.clone(),
-            }).unwrap();
+async fn post_login(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ApiJson(login): ApiJson<Login>,
+) -> Response {
+    debug!("post_login");
 
-            // Reply
-            let reply = Json(entries.clone());
-            *cached_entries = Cached::Computed {
-                commit_id: head_commit.id(),
-                data: entries,
-            };
-            reply
-        },
-        Cache::None => {
-            // Create a new list
+    const ATTEMPT_WINDOW: time::Duration = time::Duration::from_secs(60);
 
-            // Find the head commit and tree
-            let head = repo.head().unwrap();
-            let head_commit = head.peel_to_commit().unwrap();
-            let head_tree = head.peel_to_tree().unwrap();
+    // Count every attempt before verifying the password, which is what takes time
+    let ip = client_ip(&state.config, addr, &headers);
+    {
+        let mut login_attempts = state.login_attempts.lock().await;
+        // Forget attempts which are old enough
+        login_attempts.retain(|_, (_, since)| since.elapsed() < ATTEMPT_WINDOW);
+        let (count, since) = login_attempts.entry(ip).or_insert((0, time::Instant::now()));
+        if *count >= state.config.login_max_attempts {
+            let retry_after = (ATTEMPT_WINDOW - since.elapsed()).as_secs() + 1;
+            return (
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                ApiError::new(StatusCode::TOO_MANY_REQUESTS, "too_many_requests", "too many login attempts"),
+            ).into_response();
+        }
+        *count += 1;
+    }
 
-            // Load the head tree into an index
-            let mut index = Index::new().unwrap();
-            index.read_tree(&head_tree).unwrap();
-
-            // Populate the list
-            let mut oid_path_map: HashMap<Oid, PathBuf> = HashMap::new();
-            for entry in index.iter() {
-                let path = PathBuf::from(OsStr::from_bytes(&entry.path));
-                oid_path_map.insert(entry.id, path);
-            }
-
-            // Iterate over commit history to find out last modified time for each file
-            let mut entries = Vec::new();
-            let mut revwalk = repo.revwalk().unwrap();
-            revwalk.set_sorting(git2::Sort::TOPOLOGICAL).unwrap();
-            revwalk.push_head().unwrap();
-            'revwalk: for oid in revwalk {
-                let oid = oid.unwrap();
-                let commit = repo.find_commit(oid).unwrap();
-                let tree = commit.tree().unwrap();
-                debug!("{:?}", commit);
-
-                for parent in commit.parents() {
-                    let parent_tree = parent.tree().unwrap();
-                    let diff = repo.diff_tree_to_tree(Some(&parent_tree), Some(&tree), None).unwrap();
-                    for delta in diff.deltas() {
-                        use git2::Delta;
-                        match delta.status() {
-                            Delta::Added | Delta::Modified => {
-                                let file = delta.new_file();
-                                let found = {
-                                    if let Some(path) = oid_path_map.get(&file.id()) {
-                                        path == file.path().unwrap()
-                                    }
-                                    else {
-                                        false
-                                    }
-                                };
-                                if found {
-                                    // Remove the entry from oid_path_map
-                                    let path = oid_path_map.remove(&file.id()).unwrap();
-                                    // Guess the mime type
-                                    let guess = mime_guess::from_path(&path);
-                                    let mime_type = if let Some(mime) = guess.first() {
-                                        mime.as_ref().parse().unwrap()
-                                    }
-                                    else {
-                                        "application/octet-stream".to_string()
-                                    };
-                                    // Get the file size
-                                    let blob = repo.find_blob(file.id()).unwrap();
-                                    let size = blob.size();
-                                    // Extract metadata
-                                    let (metadata, title) = extract_metadata(blob.content());
-                                    // Time
-                                    let t = commit.time();
-                                    let tz = FixedOffset::east_opt(t.offset_minutes() * 60).unwrap();
-                                    let time = tz.timestamp_opt(t.seconds(), 0).unwrap();
-                                    // Add an entry
-                                    debug!("{:?} {:?} {:?}", time, delta.status(), path);
-                                    entries.push(ListEntry {
-                                        path: path,
-                                        size: size,
-                                        mime_type: mime_type,
-                                        metadata: metadata,
-                                        title: title,
-                                        time: time,
-                                    });
-                                    // Finish if all of the entries have been processed
-                                    if oid_path_map.is_empty() {
-                                        break 'revwalk;
-                                    }
-                                }
-                            },
-                            _ => (),
-                        }
-                    }
-                }
-            }
+    // Verify the password without holding the users, as it takes time
+    let user = state.config.users.lock().await.get(&login.user).cloned();
+    let matched = match user {
+        Some(user) => tokio::task::spawn_blocking(move || {
+            argon2::verify_encoded(&user.argon2_hash, login.password.as_ref())
+                .unwrap_or(false)
+                .then_some(user)
+        }).await.unwrap_or(None),
+        None => None,
+    };
 
-            // Save to a cache file
-            let mut cache_file = File::create("cache.msgpack").unwrap();
-            rmp_serde::encode::write(&mut cache_file, &models::EntriesCache {
-                commit_id: head_commit.id().to_string(),
-                entries: entries.clone(),
-            }).unwrap();
+    if let Some(user) = matched {
+        metrics::counter!("moried_logins_total", "result" => "success").increment(1);
+        state.login_attempts.lock().await.remove(&ip);
+        issue_token(&state.config, login.user, user.email).into_response()
+    }
+    else {
+        metrics::counter!("moried_logins_total", "result" => "failure").increment(1);
+        ApiError::unauthorized("invalid user name or password").into_response()
+    }
+}
 
-            // Reply
-            let reply = Json(entries.clone());
-            *cached_entries = Cached::Computed {
-                commit_id: head_commit.id(),
-                data: entries,
-            };
-            reply
-        },
+/// Tells the address of the client, which is the last one in `X-Forwarded-For` as added by the
+/// reverse proxy if MORIED_TRUST_PROXY is set, or that of the connection otherwise.
+fn client_ip(config: &Config, addr: SocketAddr, headers: &HeaderMap) -> std::net::IpAddr {
+    if config.trust_proxy {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|ip| ip.trim().parse().ok());
+        if let Some(ip) = forwarded {
+            return ip;
+        }
     }
+    addr.ip()
 }
 
-async fn get_notes_path(
-    Path(path): Path<String>,
+async fn post_password(
     State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+    ApiJson(change): ApiJson<PasswordChange>,
 ) -> Response {
-    debug!("get_notes_path");
+    debug!("post_password");
 
-    // Find a file at the given path
-    let found = {
-        let repo = state.repo.lock().await;
+    const MIN_PASSWORD_LENGTH: usize = 8;
 
-        let head = repo.head().unwrap();
-        let head_tree = head.peel_to_tree().unwrap();
+    // Hash without holding the users, as it takes time
+    let old_hash = {
+        let users = state.config.users.lock().await;
+        if users.file.is_none() {
+            return ApiError::new(StatusCode::NOT_IMPLEMENTED, "not_implemented", "the password of a user configured by environment variables cannot be changed").into_response();
+        }
+        match users.users.get(&claims.sub) {
+            Some(user) => user.argon2_hash.clone(),
+            None => return ApiError::unauthorized("unknown user").into_response(),
+        }
+    };
 
-        let mut index = Index::new().unwrap();
-        index.read_tree(&head_tree).unwrap();
+    if change.new_password.chars().count() < MIN_PASSWORD_LENGTH {
+        return ApiError::bad_request(format!("new password must be at least {} characters long", MIN_PASSWORD_LENGTH)).into_response();
+    }
+    if change.new_password == change.old_password {
+        return ApiError::bad_request("new password must differ from the old one").into_response();
+    }
 
-        index.iter().find(|entry| std::str::from_utf8(&entry.path).unwrap() == path)
-    };
-    if let Some(entry) = found {
-        let found = {
-            let repo = state.repo.lock().await;
-            repo.find_blob(entry.id).map(|blob| Vec::from(blob.content()))
-        };
-        match found {
-            Ok(content) => {
-                let mut res = content.into_response();
-                // Guess the mime type
-                let guess = mime_guess::from_path(std::str::from_utf8(&entry.path).unwrap());
-                if let Some(mime) = guess.first() {
-                    res.headers_mut().insert(header::CONTENT_TYPE, mime.as_ref().parse().unwrap()).unwrap();
-                }
-                res
-            },
-            Err(_) => StatusCode::NOT_FOUND.into_response(),
+    let verified_hash = old_hash.clone();
+    let hashed = tokio::task::spawn_blocking(move || {
+        if !argon2::verify_encoded(&verified_hash, change.old_password.as_ref()).unwrap_or(false) {
+            return Ok(None);
         }
+        let salt = Uuid::new_v4();
+        argon2::hash_encoded(change.new_password.as_ref(), salt.as_bytes(), &argon2::Config::default()).map(Some)
+    }).await.map_err(|e| e.to_string()).and_then(|hashed| hashed.map_err(|e| e.to_string()));
+    let new_hash = match hashed {
+        Ok(Some(new_hash)) => new_hash,
+        Ok(None) => return ApiError::new(StatusCode::FORBIDDEN, "forbidden", "old password is incorrect").into_response(),
+        Err(e) => return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "internal", format!("failed to hash the password: {}", e)).into_response(),
+    };
+
+    let mut users = state.config.users.lock().await;
+    match users.users.get_mut(&claims.sub) {
+        // Do not undo a change made meanwhile
+        Some(user) if user.argon2_hash == old_hash => user.argon2_hash = new_hash,
+        Some(_) => return ApiError::new(StatusCode::CONFLICT, "conflict", "the password has been changed meanwhile").into_response(),
+        None => return ApiError::unauthorized("unknown user").into_response(),
     }
-    else {
-        StatusCode::NOT_FOUND.into_response()
+    if let Err(e) = users.save() {
+        return ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "io", format!("failed to save users: {}", e)).into_response();
     }
+
+    Json(true).into_response()
 }
 
-async fn put_notes_path(
-    Path(path): Path<String>,
+async fn post_refresh(
     State(state): State<Arc<AppState>>,
-    Json(note_save): Json<NoteSave>,
+    Extension(claims): Extension<Claims>,
 ) -> Response {
-    debug!("put_notes_path");
-    debug!("{:?}", note_save);
-
-    match note_save {
-        NoteSave::Save { content, message } => {
-            let repo = state.repo.lock().await;
-
-            let head = repo.head().unwrap();
-            let head_tree = head.peel_to_tree().unwrap();
-            let head_commit = head.peel_to_commit().unwrap();
-
-            let mut index = Index::new().unwrap();
-            index.read_tree(&head_tree).unwrap();
-
-            let blob_oid = repo.blob(content.as_bytes()).unwrap();
-            let entry = IndexEntry {
-                ctime: IndexTime::new(0, 0),
-                mtime: IndexTime::new(0, 0),
-                dev: 0,
-                ino: 0,
-                mode: 0o100644,
-                uid: 0,
-                gid: 0,
-                file_size: 0,
-                id: blob_oid,
-                flags: 0,
-                flags_extended: 0,
-                path: path.as_bytes().into(),
-            };
-            index.add(&entry).unwrap();
-
-            let tree_oid = index.write_tree_to(&repo).unwrap();
-            let tree = repo.find_tree(tree_oid).unwrap();
-
-            let signature = repo.signature().unwrap();
-            repo.commit(
-                Some("HEAD"),
-                &signature,
-                &signature,
-                &message,
-                &tree,
-                &[&head_commit],
-            ).unwrap();
-            Json(&true).into_response()
-        },
-        NoteSave::Rename { from } => {
-            let found = {
-                let repo = state.repo.lock().await;
-
-                let head = repo.head().unwrap();
-                let head_tree = head.peel_to_tree().unwrap();
-
-                let mut index = Index::new().unwrap();
-                index.read_tree(&head_tree).unwrap();
+    debug!("post_refresh");
+    // The token has already been validated by `auth`
+    issue_token(&state.config, claims.sub, claims.email).into_response()
+}
 
-                index.iter().find(|entry| std::str::from_utf8(&entry.path).unwrap() == from)
-            };
-            if let Some(mut entry) = found {
-                let repo = state.repo.lock().await;
+fn issue_token(config: &Config, user: String, email: String) -> String {
+    let now: DateTime<Utc> = Utc::now();
+    let my_claims = Claims {
+        sub: user,
+        exp: (now + config.token_ttl).timestamp() as usize,
+        email,
+        jti: Uuid::new_v4().to_string(),
+    };
+    jwt::encode(
+        &jwt::Header::default(),
+        &my_claims,
+        &config.encoding_key
+    ).unwrap()
+}
 
-                let head = repo.head().unwrap();
-                let head_tree = head.peel_to_tree().unwrap();
-                let head_commit = head.peel_to_commit().unwrap();
+async fn post_logout(
+    State(state): State<Arc<AppState>>,
+    Extension(claims): Extension<Claims>,
+) -> Json<bool> {
+    debug!("post_logout");
 
-                let mut index = Index::new().unwrap();
-                index.read_tree(&head_tree).unwrap();
+    let now = Utc::now().timestamp() as usize;
+    let mut revoked_tokens = state.revoked_tokens.lock().await;
+    // Forget tokens which have expired anyway
+    revoked_tokens.retain(|_, exp| *exp > now);
+    revoked_tokens.insert(claims.jti, claims.exp);
 
-                let from = std::str::from_utf8(&entry.path).unwrap();
-                index.remove(from.as_ref(), 0).unwrap();
+    Json(true)
+}
 
-                let message = format!("Rename {} to {}", &from, &path);
-                entry.path = path.as_bytes().into();
-                index.add(&entry).unwrap();
+async fn get_notes(
+    ApiQuery(query): ApiQuery<ListQuery>,
+    ApiQuery(params): ApiQuery<Vec<(String, String)>>,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    debug!("get_notes");
 
-                let tree_oid = index.write_tree_to(&repo).unwrap();
-                let tree = repo.find_tree(tree_oid).unwrap();
+    let mut entries = match list_entries(&state).await {
+        Ok(entries) => entries,
+        Err(e) => return e.into_response(),
+    };
 
-                let signature = repo.signature().unwrap();
-                repo.commit(
-                    Some("HEAD"),
-                    &signature,
-                    &signature,
-                    &message,
-                    &tree,
-                    &[&head_commit],
-                ).unwrap();
-                Json(&true).into_response()
+    // Filter by metadata given as `meta.<field>=<value>`, or `tag=<value>` for short
+    let filters: Vec<(&str, &str)> = params
+        .iter()
+        .filter_map(|(key, value)| {
+            if key == "tag" {
+                Some(("tags", value.as_str()))
             }
             else {
-                StatusCode::NOT_FOUND.into_response()
+                key.strip_prefix("meta.").map(|field| (field, value.as_str()))
             }
-        },
+        })
+        .collect();
+    if !filters.is_empty() {
+        entries.retain(|entry| {
+            filters.iter().all(|(field, expected)| {
+                metadata_field(entry, field).is_some_and(|value| metadata_matches(value, expected))
+            })
+        });
+    }
+
+    if query.with_history {
+        if let Err(e) = fill_last_commits(&state, &mut entries).await {
+            return e.into_response();
+        }
+    }
+
+    // Sort by path unless requested otherwise, so that the order is deterministic
+    if let Err(e) = sort_entries(&mut entries, query.sort.as_deref().unwrap_or("path")) {
+        return e.into_response();
+    }
+
+    if query.offset.is_none() && query.limit.is_none() {
+        // Reply with the whole list
+        Json(entries).into_response()
+    }
+    else {
+        // Reply with the requested page
+        let total = entries.len();
+        let offset = query.offset.unwrap_or(0);
+        let limit = query.limit.unwrap_or(total);
+        let entries = entries.into_iter().skip(offset).take(limit).collect();
+        Json(ListPage {
+            total,
+            entries,
+        }).into_response()
     }
 }
 
-async fn delete_notes_path(
-    Path(path): Path<String>,
+/// Counts notes by the tags in their metadata.
+async fn get_note_tags(
     State(state): State<Arc<AppState>>,
-) -> Response {
-    debug!("delete_notes_path");
-
-    let found = {
-        let repo = state.repo.lock().await;
+) -> Result<Json<BTreeMap<String, usize>>, GitError> {
+    debug!("get_note_tags");
 
-        let head = repo.head().unwrap();
-        let head_tree = head.peel_to_tree().unwrap();
+    let entries = list_entries(&state).await?;
 
-        let mut index = Index::new().unwrap();
-        index.read_tree(&head_tree).unwrap();
+    let mut counts = BTreeMap::new();
+    for entry in &entries {
+        // Either a list of tags or a single one
+        let tags = match metadata_field(entry, "tags") {
+            Some(Metadata::Sequence(tags)) => tags.iter().filter_map(|tag| tag.as_str()).collect(),
+            Some(Metadata::String(tag)) => vec![tag.as_str()],
+            _ => Vec::new(),
+        };
+        for tag in tags {
+            *counts.entry(tag.to_owned()).or_insert(0) += 1;
+        }
+    }
 
-        index.iter().find(|entry| std::str::from_utf8(&entry.path).unwrap() == path)
-    };
-    if let Some(entry) = found {
-        let repo = state.repo.lock().await;
+    Ok(Json(counts))
+}
 
-        let head = repo.head().unwrap();
-        let head_tree = head.peel_to_tree().unwrap();
-        let head_commit = head.peel_to_commit().unwrap();
+/// Lists git tags marking snapshots of the repository.
+async fn get_tags(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<GitTag>>, GitError> {
+    debug!("get_tags");
 
-        let mut index = Index::new().unwrap();
-        index.read_tree(&head_tree).unwrap();
+    let repo = state.repo.read().await?;
+    let mut tags = Vec::new();
+    for name in repo.tag_names(None)?.iter().flatten() {
+        tags.push(git_tag(&repo, name)?);
+    }
+    Ok(Json(tags))
+}
 
-        let path = std::str::from_utf8(&entry.path).unwrap();
-        index.remove(path.as_ref(), 0).unwrap();
+/// Creates an annotated tag on HEAD on behalf of the user.
+async fn post_tags(
+    State(state): State<Arc<AppState>>,
+    claims: Option<Extension<Claims>>,
+    ApiJson(new_tag): ApiJson<NewTag>,
+) -> Result<Response, GitError> {
+    debug!("post_tags");
 
-        let tree_oid = index.write_tree_to(&repo).unwrap();
-        let tree = repo.find_tree(tree_oid).unwrap();
+    if !git2::Reference::is_valid_name(&format!("refs/tags/{}", new_tag.name)) {
+        return Ok(ApiError::bad_request(format!("invalid tag name: {:?}", new_tag.name)).into_response());
+    }
 
-        let signature = repo.signature().unwrap();
-        repo.commit(
-            Some("HEAD"),
-            &signature,
-            &signature,
-            &format!("Delete {}", &path),
-            &tree,
-            &[&head_commit],
-        ).unwrap();
-        Json(&true).into_response()
+    let repo = state.repo.write().await?;
+    if repo.find_reference(&format!("refs/tags/{}", new_tag.name)).is_ok() {
+        return Ok(ApiError::new(StatusCode::CONFLICT, "conflict", format!("tag {} already exists", new_tag.name)).into_response());
     }
-    else {
-        StatusCode::NOT_FOUND.into_response()
+    if repo.is_unborn() {
+        return Ok(ApiError::new(StatusCode::CONFLICT, "conflict", "there is no commit to tag yet").into_response());
     }
+    let head_commit = repo.head_commit()?;
+    let tagger = signature_for(&repo, claims.as_deref())?;
+    repo.tag(&new_tag.name, head_commit.as_object(), &tagger, &new_tag.message, false)?;
+
+    Ok((StatusCode::CREATED, Json(git_tag(&repo, &new_tag.name)?)).into_response())
 }
 
-async fn get_files_path(
-    Path(path): Path<String>,
-    State(state): State<Arc<AppState>>,
-) -> Response {
-    debug!("get_files_path");
+fn git_tag(repo: &Repository, name: &str) -> Result<GitTag, GitError> {
+    let reference = repo.find_reference(&format!("refs/tags/{}", name))?;
+    Ok(GitTag {
+        name: name.to_owned(),
+        // Usually a commit, but tags may point at trees or blobs as well
+        target: reference.peel(git2::ObjectType::Any)?.id().to_string(),
+        // Lightweight tags have no message
+        message: reference.peel_to_tag().ok().and_then(|tag| tag.message().map(str::to_owned)),
+    })
+}
 
-    let found = {
-        let repo = state.repo.lock().await;
+fn metadata_field<'a>(entry: &'a ListEntry, field: &str) -> Option<&'a Metadata> {
+    entry.metadata.as_ref().and_then(|metadata| metadata.get(field))
+}
 
-        let head = repo.head().unwrap();
-        let head_tree = head.peel_to_tree().unwrap();
+/// Checks if a metadata value equals the expected one, or contains it if the value is a list.
+fn metadata_matches(value: &Metadata, expected: &str) -> bool {
+    match value {
+        Metadata::Sequence(values) => values.iter().any(|value| metadata_matches(value, expected)),
+        Metadata::String(s) => s == expected,
+        Metadata::Number(n) => n.to_string() == expected,
+        Metadata::Bool(b) => b.to_string() == expected,
+        _ => false,
+    }
+}
 
-        let mut index = Index::new().unwrap();
-        index.read_tree(&head_tree).unwrap();
+/// Sorts entries by `path`, `modified` time or a metadata field given as `meta.<field>`.
+/// A leading `-` reverses the order.
+fn sort_entries(entries: &mut [ListEntry], sort: &str) -> Result<(), ApiError> {
+    use std::cmp::Ordering;
 
-        index.iter().find(|entry| std::str::from_utf8(&entry.path).unwrap() == path)
+    let (key, descending) = match sort.strip_prefix('-') {
+        Some(key) => (key, true),
+        None => (sort, false),
     };
-    if let Some(entry) = found {
-        let found = {
-            let repo = state.repo.lock().await;
-            repo.find_blob(entry.id).map(|blob| Vec::from(blob.content()))
-        };
-        match found {
-            Ok(content) => {
-                let mut res = content.into_response();
-                // Guess the mime type
-                let guess = mime_guess::from_path(std::str::from_utf8(&entry.path).unwrap());
-                if let Some(mime) = guess.first() {
-                    res.headers_mut().insert(header::CONTENT_TYPE, mime.as_ref().parse().unwrap()).unwrap();
-                }
-                res
-            },
-            Err(_) => StatusCode::NOT_FOUND.into_response()
-        }
+    let directed = |ordering: Ordering| if descending { ordering.reverse() } else { ordering };
+
+    if key == "path" {
+        entries.sort_by(|a, b| directed(a.path.cmp(&b.path)));
+    }
+    else if key == "modified" {
+        entries.sort_by(|a, b| directed(a.time.cmp(&b.time)));
+    }
+    else if let Some(field) = key.strip_prefix("meta.") {
+        entries.sort_by(|a, b| {
+            match (metadata_field(a, field), metadata_field(b, field)) {
+                (Some(x), Some(y)) => directed(x.partial_cmp(y).unwrap_or(Ordering::Equal)),
+                // Entries without the field come last
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            }
+        });
     }
     else {
-        StatusCode::NOT_FOUND.into_response()
+        return Err(ApiError::bad_request(format!("unknown sort key: {}", key)));
     }
+    Ok(())
 }
 
-async fn post_files(
-    State(state): State<Arc<AppState>>,
-    mut multipart: Multipart,
-) -> Response {
-    debug!("post_files_path");
-
-    // Create a blob for each part (file) in the form data
-    let mut files = Vec::new();
-    let mut result = Vec::new();
-    while let Some(field) = multipart.next_field().await.unwrap() {
-        debug!("{:?}", field);
+/// Fills in the newest commit touching each entry, found in a single walk over the history.
+async fn fill_last_commits(state: &AppState, entries: &mut [ListEntry]) -> Result<(), GitError> {
+    let repo = state.repo.read().await?;
 
-        let uuid = field.name().unwrap().to_owned();
-        let filename = field.file_name().unwrap().as_bytes().to_vec();
+    let mut remaining: HashMap<PathBuf, usize> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, entry)| (entry.path.clone(), i))
+        .collect();
 
-        let blob_oid = {
-            let data = field.bytes().await.unwrap();
+    let revwalk = branch_revwalk(&repo)?;
+    for oid in revwalk {
+        // Finish if all of the entries have been processed
+        if remaining.is_empty() {
+            break;
+        }
 
-            let repo = state.repo.lock().await;
-            let mut writer = repo.blob_writer(None).unwrap();
-            writer.write_all(&data).unwrap();
-            writer.commit().unwrap()
+        let commit = repo.find_commit(oid?)?;
+        let tree = commit.tree()?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
         };
-
-        files.push((filename, blob_oid));
-        result.push((uuid, "success"));
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+        for delta in diff.deltas() {
+            if let Some(i) = delta.new_file().path().and_then(|path| remaining.remove(path)) {
+                entries[i].last_commit = Some(LastCommit {
+                    id: commit.id().to_string(),
+                    author: commit.author().name().unwrap_or_default().to_owned(),
+                    time: commit_time(&commit),
+                });
+            }
+        }
     }
 
-    // Commit
-    let repo = state.repo.lock().await;
-
-    let head = repo.head().unwrap();
-    let head_tree = head.peel_to_tree().unwrap();
-    let head_commit = head.peel_to_commit().unwrap();
-
-    let mut index = Index::new().unwrap();
-    index.read_tree(&head_tree).unwrap();
-
-    let count = files.len();
-    for (path, blob_oid) in files {
-        let entry = IndexEntry {
-            ctime: IndexTime::new(0, 0),
-            mtime: IndexTime::new(0, 0),
-            dev: 0,
-            ino: 0,
-            mode: 0o100644,
-            uid: 0,
-            gid: 0,
-            file_size: 0,
-            id: blob_oid,
-            flags: 0,
-            flags_extended: 0,
-            path: path,
-        };
-        index.add(&entry).unwrap();
-    }
-
-    let tree_oid = index.write_tree_to(&repo).unwrap();
-    let tree = repo.find_tree(tree_oid).unwrap();
-
-    let signature = repo.signature().unwrap();
-    repo.commit(
-        Some("HEAD"),
-        &signature,
-        &signature,
-        &format!("Upload {} files", count),
-        &tree,
-        &[&head_commit],
-    ).unwrap();
+    Ok(())
+}
 
-    Json(result).into_response()
+/// Lists notes in HEAD, i.e. files with MORIED_NOTE_EXTENSIONS if set, except for those in the trash.
+async fn list_entries(state: &AppState) -> Result<Vec<ListEntry>, GitError> {
+    let mut entries = list_all_entries(state).await?;
+    entries.retain(|entry| state.config.is_note(&entry.path) && !state.config.in_trash(&entry.path));
+    Ok(entries)
 }
 
-fn get_frontmatter_node(node: &markdown::mdast::Node) -> Option<&markdown::mdast::Node> {
-    use markdown::mdast::Node;
-    node.children().and_then(|children| children.get(0)).and_then(|first_child_node| {
-        match first_child_node {
-            Node::Yaml(_) | Node::Toml(_) => {
-                Some(first_child_node)
-            },
-            _ => {
-                None
-            },
-        }
-    })
+/// Lists every file in HEAD, which is cached for the commit.
+async fn list_all_entries(state: &AppState) -> Result<Vec<ListEntry>, GitError> {
+    // Check if a cache exists
+    let repo = state.repo.read().await?;
+    if repo.is_unborn() {
+        return Ok(Vec::new());
+    }
+    let mut cached_entries = state.cached_entries.lock().await;
+    let head_commit = repo.head_commit()?;
+    let entries = match cached_entries.get(&repo) {
+        Cache::Valid(entries) => {
+            // Return the cache
+            return Ok(entries.clone());
+        },
+        // The cache may be for a commit this branch does not descend from, e.g. one written for another repository,
+        // or one whose history has been rewritten since, so only a cache for an ancestor is updated
+        Cache::Invalid(last_commit_id, old_entries) if repo.graph_descendant_of(head_commit.id(), last_commit_id).unwrap_or(false) => {
+            update_entries(state, &repo, last_commit_id, old_entries)?
+        },
+        Cache::Invalid(..) | Cache::None => {
+            // Create a new list
+            build_entries(state, &repo, &head_commit)?
+        },
+    };
+
+    // Save to a cache file, without which the list is only rebuilt at the next start
+    if let Err(e) = save_entries(&state.config.cache_file, head_commit.id(), &entries) {
+        warn!("failed to save the cache to {:?}: {}", state.config.cache_file, e);
+    }
+
+    // Reply
+    let reply = entries.clone();
+    *cached_entries = Cached::Computed {
+        commit_id: head_commit.id(),
+        data: entries,
+    };
+    Ok(reply)
 }
 
-fn get_first_toplevel_rank1_heading(node: &markdown::mdast::Node) -> Option<&markdown::mdast::Node> {
-    use markdown::mdast::Node;
-    if let Node::Root(root) = node {
-        for child in root.children.iter() {
-            if let Node::Heading(heading) = child {
-                if heading.depth == 1 {
-                    return Some(child);
+/// Writes the list for the commit to the cache file, from which it is read at startup.
+fn save_entries(path: &std::path::Path, commit_id: Oid, entries: &[ListEntry]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut cache_file = File::create(path)?;
+    rmp_serde::encode::write(&mut cache_file, &models::EntriesCache {
+        commit_id: commit_id.to_string(),
+        entries: entries.to_vec(),
+    })?;
+    Ok(())
+}
+
+/// Updates the list cached for an ancestor of HEAD with the files changed since.
+fn update_entries(state: &AppState, repo: &Repo, last_commit_id: Oid, old_entries: &[ListEntry]) -> Result<Vec<ListEntry>, GitError> {
+    use git2::Delta;
+
+    // Iterate over recent commit history to collect operations on files
+    let mut latest_ops: HashMap<PathBuf, (Delta, DateTime<FixedOffset>, Oid)> = HashMap::new();
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+    revwalk.push_range(&format!("{}..{}", last_commit_id, repo.head_ref()))?;
+    for oid in revwalk {
+        let oid = oid?;
+        let commit = repo.find_commit(oid)?;
+        debug!("{:?}", commit);
+
+        let time = commit_time(&commit);
+
+        let tree = commit.tree()?;
+        for parent in commit.parents() {
+            let parent_tree = parent.tree()?;
+            let diff = repo.diff_tree_to_tree(Some(&parent_tree), Some(&tree), None)?;
+            for delta in diff.deltas() {
+                match delta.status() {
+                    Delta::Added | Delta::Modified => {
+                        let file = delta.new_file();
+                        let Some(path) = file.path() else { continue };
+                        let path = path.to_owned();
+                        latest_ops.entry(path).or_insert((
+                            delta.status(),
+                            time,
+                            file.id(),
+                        ));
+                    },
+                    Delta::Deleted => {
+                        let file = delta.old_file();
+                        let Some(path) = file.path() else { continue };
+                        let path = path.to_owned();
+                        latest_ops.entry(path).or_insert((
+                            delta.status(),
+                            time,
+                            file.id(),
+                        ));
+                    },
+                    _ => (),
                 }
             }
         }
-        None
     }
-    else {
-        None
+
+    // Update existing entries
+    let mut entries: Vec<ListEntry> = Vec::with_capacity(old_entries.len());
+    for entry in old_entries {
+        let mut found = false;
+        match latest_ops.get(&entry.path) {
+            None => {
+                // Entry is untouched
+                entries.push(entry.clone());
+            },
+            Some(&(op, time, blob_id)) => {
+                // Entry is modified or deleted
+                found = true;
+                match op {
+                    Delta::Added | Delta::Modified => {
+                        // Get the file size
+                        let blob = repo.find_blob(blob_id)?;
+                        let size = blob.size();
+                        // Extract metadata
+                        let (metadata, title) = note_metadata(state, &entry.path, blob.content());
+                        // Add an entry
+                        entries.push(ListEntry {
+                            path: entry.path.to_owned(),
+                            size,
+                            mime_type: entry.mime_type.to_owned(),
+                            metadata,
+                            title,
+                            time,
+                            last_commit: None,
+                        });
+                    },
+                    Delta::Deleted => {
+                        // Ignore the entry
+                    },
+                    _ => unreachable!(),
+                }
+            },
+        }
+        if found {
+            latest_ops.remove(&entry.path);
+        }
+    }
+
+    // Add newly created entries
+    for (path, (op, time, blob_id)) in latest_ops {
+        match op {
+            Delta::Added | Delta::Modified => {
+                // Guess the mime type
+                let mime_type = guess_mime_type(&path);
+                // Get the file size
+                let blob = repo.find_blob(blob_id)?;
+                let size = blob.size();
+                // Extract metadata
+                let (metadata, title) = note_metadata(state, &path, blob.content());
+                // Add an entry
+                entries.push(ListEntry {
+                    path,
+                    size,
+                    mime_type,
+                    metadata,
+                    title,
+                    time,
+                    last_commit: None,
+                });
+            },
+            Delta::Deleted => {
+                // Ignore the entry
+            },
+            _ => unreachable!(),
+        }
     }
+
+    Ok(entries)
 }
 
-fn extract_metadata(blob: &[u8]) -> (Option<serde_yaml::Value>, Option<String>) {
-    if let Ok(text) = std::str::from_utf8(blob) {
-        let mut opts = markdown::ParseOptions::gfm();
-        opts.constructs.frontmatter = true;
-        if let Ok(node) = markdown::to_mdast(text, &opts) {
-            let metadata = if let Some(markdown::mdast::Node::Yaml(yaml_node)) = get_frontmatter_node(&node) {
-                match serde_yaml::from_str::<serde_yaml::Value>(&yaml_node.value) {
-                    Ok(doc) => {
-                        debug!("parsed YAML metadata: {:?}", &doc);
-                        Some(doc)
-                    },
-                    Err(err) => {
-                        debug!("failed to parse YAML metadata: {:?}", &err);
-                        let mut error_object = serde_yaml::Mapping::new();
-                        error_object.insert("error".into(), format!("{}", err).into());
-                        Some(serde_yaml::Value::Mapping(error_object))
+/// Lists every file in HEAD, walking the history to find out when each one was last changed.
+fn build_entries(state: &AppState, repo: &Repo, head_commit: &git2::Commit) -> Result<Vec<ListEntry>, GitError> {
+    // Find the head tree
+    let head_tree = head_commit.tree()?;
+
+    // Load the head tree into an index
+    let mut index = Index::new()?;
+    index.read_tree(&head_tree)?;
+
+    // Populate the list
+    let mut oid_path_map: HashMap<Oid, PathBuf> = HashMap::new();
+    for entry in index.iter() {
+        let path = PathBuf::from(OsStr::from_bytes(&entry.path));
+        oid_path_map.insert(entry.id, path);
+    }
+
+    // Iterate over commit history to find out last modified time for each file
+    let mut entries = Vec::new();
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL)?;
+    revwalk.push_ref(repo.head_ref())?;
+    'revwalk: for oid in revwalk {
+        let oid = oid?;
+        let commit = repo.find_commit(oid)?;
+        let tree = commit.tree()?;
+        debug!("{:?}", commit);
+
+        // The root commit adds everything in its tree
+        let parent_trees: Vec<Option<git2::Tree>> = match commit.parent_count() {
+            0 => vec![None],
+            _ => commit.parents().map(|parent| parent.tree().map(Some)).collect::<Result<_, _>>()?,
+        };
+        for parent_tree in parent_trees {
+            let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+            for delta in diff.deltas() {
+                use git2::Delta;
+                match delta.status() {
+                    Delta::Added | Delta::Modified => {
+                        let file = delta.new_file();
+                        let found = {
+                            if let Some(path) = oid_path_map.get(&file.id()) {
+                                file.path() == Some(path.as_path())
+                            }
+                            else {
+                                false
+                            }
+                        };
+                        if found {
+                            // Remove the entry from oid_path_map
+                            let path = oid_path_map.remove(&file.id()).unwrap();
+                            // Guess the mime type
+                            let mime_type = guess_mime_type(&path);
+                            // Get the file size
+                            let blob = repo.find_blob(file.id())?;
+                            let size = blob.size();
+                            // Extract metadata
+                            let (metadata, title) = note_metadata(state, &path, blob.content());
+                            // Time
+                            let time = commit_time(&commit);
+                            // Add an entry
+                            debug!("{:?} {:?} {:?}", time, delta.status(), path);
+                            entries.push(ListEntry {
+                                path,
+                                size,
+                                mime_type,
+                                metadata,
+                                title,
+                                time,
+                                last_commit: None,
+                            });
+                            // Finish if all of the entries have been processed
+                            if oid_path_map.is_empty() {
+                                break 'revwalk;
+                            }
+                        }
                     },
+                    _ => (),
                 }
             }
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Most results replied at once by the log, histories, search and feed.
+const MAX_LIMIT: usize = 500;
+
+async fn get_search(
+    ApiQuery(query): ApiQuery<SearchQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<SearchHit>>, GitError> {
+    debug!("get_search");
+
+    let needle = if query.case_sensitive {
+        query.q.clone()
+    }
+    else {
+        query.q.to_lowercase()
+    };
+    let limit = query.limit.unwrap_or(50).min(MAX_LIMIT);
+
+    let repo = state.repo.read().await?;
+
+    let head_tree = repo.head_tree()?;
+
+    let mut index = Index::new()?;
+    index.read_tree(&head_tree)?;
+
+    let mut hits = Vec::new();
+    // Skip submodules, whose commits are not in this repository
+    'entries: for entry in index.iter().filter(|entry| entry.mode != 0o160000) {
+        let blob = repo.find_blob(entry.id)?;
+        // Skip binary blobs
+        if blob.content().contains(&0) {
+            continue;
+        }
+        // Skip non-UTF-8 blobs
+        let text = match std::str::from_utf8(blob.content()) {
+            Ok(text) => text,
+            Err(_) => continue,
+        };
+        for (i, line) in text.lines().enumerate() {
+            let found = if query.case_sensitive {
+                line.contains(&needle)
+            }
             else {
-                None
+                line.to_lowercase().contains(&needle)
             };
-            let title = get_first_toplevel_rank1_heading(&node).map(|heading_node| heading_node.to_string());
-            (metadata, title)
+            if found {
+                if hits.len() >= limit {
+                    break 'entries;
+                }
+                hits.push(SearchHit {
+                    path: PathBuf::from(OsStr::from_bytes(&entry.path)),
+                    line_number: i + 1,
+                    snippet: line.trim().to_owned(),
+                });
+            }
         }
-        else {
-            (None, None)
+    }
+
+    Ok(Json(hits))
+}
+
+async fn get_history_path(
+    RepoPath(path): RepoPath,
+    ApiQuery(query): ApiQuery<HistoryQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, GitError> {
+    debug!("get_history_path");
+
+    let limit = query.limit.unwrap_or(50).min(MAX_LIMIT);
+
+    let repo = state.repo.read().await?;
+
+    let revwalk = branch_revwalk(&repo)?;
+
+    let mut history = Vec::new();
+    for oid in revwalk {
+        if history.len() >= limit {
+            break;
+        }
+
+        let oid = oid?;
+        let commit = repo.find_commit(oid)?;
+
+        // Check if the commit touched the file at the path
+        if paths_changed_by(&repo, &commit, Some(&path))?.is_empty() {
+            continue;
         }
+
+        let author = commit.author();
+        history.push(HistoryEntry {
+            commit_id: oid.to_string(),
+            author: author.name().unwrap_or_default().to_owned(),
+            email: author.email().unwrap_or_default().to_owned(),
+            time: commit_time(&commit),
+            message: commit.message().unwrap_or_default().to_owned(),
+        });
     }
-    else {
-        (None, None)
+
+    Ok(Json(history).into_response())
+}
+
+/// Lists the commits of the served branch, newest first.
+async fn get_log(
+    ApiQuery(query): ApiQuery<LogQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<LogEntry>>, GitError> {
+    debug!("get_log");
+
+    // Do not walk a huge history at once
+    let limit = query.limit.unwrap_or(50).min(MAX_LIMIT);
+
+    let repo = state.repo.read().await?;
+
+    let revwalk = branch_revwalk(&repo)?;
+
+    let mut log = Vec::new();
+    for oid in revwalk.skip(query.offset.unwrap_or(0)).take(limit) {
+        let commit = repo.find_commit(oid?)?;
+        let author = commit.author();
+        log.push(LogEntry {
+            id: commit.id().to_string(),
+            author: author.name().unwrap_or_default().to_owned(),
+            email: author.email().unwrap_or_default().to_owned(),
+            timestamp: commit_time(&commit),
+            summary: commit.summary().unwrap_or_default().to_owned(),
+        });
     }
+
+    Ok(Json(log))
 }
 
-mod models {
-    use std::fs::File;
-    use std::path::PathBuf;
-    use std::sync::Arc;
-    use std::option::Option;
+async fn get_feed(
+    ApiQuery(query): ApiQuery<HistoryQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, GitError> {
+    debug!("get_feed");
 
-    use chrono::{DateTime, FixedOffset};
-    use git2::{Repository, Oid};
-    use serde::{Deserialize, Serialize};
-    use tokio::sync::Mutex;
+    let limit = query.limit.unwrap_or(50).min(MAX_LIMIT);
 
-    pub type Metadata = serde_yaml::Value;
+    let repo = state.repo.read().await?;
 
-    #[derive(Debug, Deserialize, Serialize, Clone)]
-    pub struct ListEntry {
-        pub path: PathBuf,
-        pub size: usize,
-        pub mime_type: String,
-        pub metadata: Option<Metadata>,
-        pub title: Option<String>,
-        pub time: DateTime<FixedOffset>,
+    let revwalk = branch_revwalk(&repo)?;
+
+    let mut entries = String::new();
+    let mut updated = None;
+    for oid in revwalk.take(limit) {
+        let commit = repo.find_commit(oid?)?;
+        let time = commit_time(&commit);
+        updated.get_or_insert(time);
+
+        // Link to the notes changed by the commit
+        let tree = commit.tree()?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), None)?;
+        let mut links = String::new();
+        for delta in diff.deltas() {
+            if delta.status() == git2::Delta::Deleted {
+                continue;
+            }
+            if let Some(path) = delta.new_file().path().and_then(|path| path.to_str()) {
+                let href: Vec<_> = path.split('/').map(urlencoding::encode).collect();
+                links.push_str(&format!(
+                    "    <link rel=\"related\" href=\"{}notes/{}\" title=\"{}\"/>\n",
+                    escape_xml(&state.config.root_path),
+                    escape_xml(&href.join("/")),
+                    escape_xml(path),
+                ));
+            }
+        }
+
+        let author = commit.author();
+        entries.push_str(&format!(
+            concat!(
+                "  <entry>\n",
+                "    <id>urn:sha1:{}</id>\n",
+                "    <title>{}</title>\n",
+                "    <updated>{}</updated>\n",
+                "    <author><name>{}</name><email>{}</email></author>\n",
+                // An entry without an alternate link needs content
+                "    <content type=\"text\">{}</content>\n",
+                "{}",
+                "  </entry>\n",
+            ),
+            commit.id(),
+            escape_xml(commit.summary().unwrap_or_default()),
+            time.to_rfc3339(),
+            escape_xml(author.name().unwrap_or_default()),
+            escape_xml(author.email().unwrap_or_default()),
+            escape_xml(commit.message().unwrap_or_default().trim_end()),
+            links,
+        ));
     }
 
-    #[derive(Debug)]
-    pub struct Unauthorized;
+    let feed = format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+            "<feed xmlns=\"http://www.w3.org/2005/Atom\">\n",
+            "  <id>urn:moried:feed</id>\n",
+            "  <title>Recent changes</title>\n",
+            "  <updated>{}</updated>\n",
+            "{}",
+            "</feed>\n",
+        ),
+        updated.unwrap_or_else(|| Utc::now().into()).to_rfc3339(),
+        entries,
+    );
+    Ok(([(header::CONTENT_TYPE, "application/atom+xml")], feed).into_response())
+}
 
-    #[derive(Debug)]
-    pub struct NotFound;
+fn escape_xml(text: &str) -> String {
+    text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
 
-    #[derive(Debug, Serialize, Deserialize)]
-    pub struct Claims {
-        pub sub: String,
-        pub exp: usize,
-        pub email: String,
+/// What is asked for by a suffix to the path of a note, e.g. `<path>/blame`.
+enum NoteView<'a> {
+    Diff(&'a str),
+    Blame(&'a str),
+    Meta(&'a str),
+}
+
+impl NoteView<'_> {
+    fn parse(path: &str) -> Option<NoteView<'_>> {
+        let (file, suffix) = path.rsplit_once('/')?;
+        match suffix {
+            "diff" => Some(NoteView::Diff(file)),
+            "blame" => Some(NoteView::Blame(file)),
+            "meta" => Some(NoteView::Meta(file)),
+            _ => None,
+        }
     }
+}
 
-    pub enum Cache<'a, T> {
-        Valid(&'a T),
-        Invalid(Oid, &'a T),
-        None,
+async fn get_notes_path(
+    RepoPath(path): RepoPath,
+    ApiQuery(query): ApiQuery<NoteQuery>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    debug!("get_notes_path");
+
+    let repo = match state.repo.read().await {
+        Ok(repo) => repo,
+        Err(e) => return e.into_response(),
+    };
+
+    if let Some(range) = &query.diff {
+        let Some((from, to)) = range.split_once("..") else {
+            return ApiError::bad_request("diff must be given as <oid_a>..<oid_b>").into_response();
+        };
+        return diff_note(&repo, &path, from, Some(to)).unwrap_or_else(|e| e.into_response());
     }
 
-    pub enum Cached<T> {
-        Computed {
-            commit_id: Oid,
-            data: T,
-        },
-        None,
+    // <path>/diff, <path>/blame and <path>/meta are taken as such unless they are files themselves
+    if let Some(view) = NoteView::parse(&path) {
+        match head_blob_id(&state, &repo, &path) {
+            Ok(Some(_)) => (),
+            Ok(None) => {
+                let viewed = match view {
+                    NoteView::Diff(file) => {
+                        let Some(from) = &query.from else {
+                            return ApiError::bad_request("missing the commit to diff from").into_response();
+                        };
+                        diff_note(&repo, file, from, query.to.as_deref())
+                    },
+                    NoteView::Blame(file) => blame_note(&repo, file),
+                    NoteView::Meta(file) => note_meta(&state, &repo, file),
+                };
+                return viewed.unwrap_or_else(|e| e.into_response());
+            },
+            Err(e) => return e.into_response(),
+        }
     }
 
-    impl<T> Cached<T> {
-        pub fn get(&self, repo: &Repository) -> Cache<T> {
-            match self {
-                Cached::None => Cache::None,
-                Cached::Computed { commit_id, data } => {
-                    let head = repo.head().unwrap();
-                    let commit = head.peel_to_commit().expect("Failed to obtain the commit of HEAD");
-                    if *commit_id == commit.id() {
-                        Cache::Valid(data)
+    // Find a file at the given path, reading from the requested revision if any, or from HEAD otherwise
+    let found = match &query.rev {
+        Some(rev) => match find_commit(&repo, rev).map(|commit| commit.tree()) {
+            Ok(Ok(tree)) => find_blob_id(&tree, &path).map(|blob_id| (blob_id, None)),
+            Ok(Err(e)) => return GitError::from(e).into_response(),
+            Err(e) => return e.into_response(),
+        },
+        None => match head_blob_id(&state, &repo, &path) {
+            Ok(Some(blob_id)) => match last_modified(&state, &repo, &path) {
+                Ok(time) => Some((blob_id, time)),
+                Err(e) => return e.into_response(),
+            },
+            Ok(None) => None,
+            Err(e) => return e.into_response(),
+        },
+    };
+    if let Some((blob_id, modified)) = found {
+        let found = repo.find_blob(blob_id).map(|blob| {
+            note_headers(&headers, &path, &blob, modified).map(|validators| (validators, Vec::from(blob.content())))
+        });
+        // Release the repository before rendering
+        drop(repo);
+        match found {
+            Ok(Err(validators)) => (StatusCode::NOT_MODIFIED, validators).into_response(),
+            Ok(Ok((validators, content))) => {
+                if let Some(render) = &query.render {
+                    if render != "html" {
+                        return ApiError::bad_request(format!("unknown rendering: {}", render)).into_response();
                     }
-                    else {
-                        Cache::Invalid(*commit_id, data)
+                    if guess_mime_type(&path) == "text/markdown" {
+                        if let Ok(text) = std::str::from_utf8(&content) {
+                            let (_, body) = split_frontmatter(text);
+                            // Raw HTML in notes is escaped rather than passed through
+                            let html = markdown::to_html_with_options(body, &markdown::Options::gfm()).unwrap_or_default();
+                            return (
+                                validators,
+                                [(header::CONTENT_TYPE, "text/html; charset=utf-8")],
+                                html,
+                            ).into_response();
+                        }
                     }
-                },
-            }
+                }
+                if accepts_json(&headers) {
+                    if let Ok(text) = std::str::from_utf8(&content) {
+                        // Reply with the body and its frontmatter parsed
+                        let (metadata, body) = split_frontmatter(text);
+                        return (
+                            validators,
+                            Json(NoteContent {
+                                content: body.to_owned(),
+                                metadata,
+                            }),
+                        ).into_response();
+                    }
+                }
+                // Guess the mime type
+                let mime_type = guess_mime_type(&path);
+                (
+                    validators,
+                    [(header::CONTENT_TYPE, mime_type.parse::<HeaderValue>().unwrap())],
+                    content,
+                ).into_response()
+            },
+            Err(_) => ApiError::not_found(format!("{} not found", path)).into_response(),
         }
     }
+    else {
+        ApiError::not_found(format!("{} not found", path)).into_response()
+    }
+}
 
-    #[derive(Deserialize, Serialize)]
-    pub struct EntriesCache {
-        pub commit_id: String,
-        pub entries: Vec<ListEntry>,
+/// Diffs the note between two commits, the newer one defaulting to HEAD.
+/// Fails telling which side is missing unless both commits exist and have the note.
+fn diff_note(repo: &Repo, path: &str, from: &str, to: Option<&str>) -> Result<Response, GitError> {
+    // Find the blob for the path at each side
+    let mut blobs = Vec::with_capacity(2);
+    for (side, rev) in [("old", Some(from)), ("new", to)] {
+        let commit = match rev {
+            Some(rev) => match Oid::from_str(rev) {
+                Ok(oid) => match repo.find_commit(oid) {
+                    Ok(commit) => commit,
+                    Err(_) => return Ok(ApiError::not_found(format!("{} side: commit {} not found", side, rev)).into_response()),
+                },
+                Err(_) => return Ok(ApiError::bad_request(format!("{} side: invalid commit id: {}", side, rev)).into_response()),
+            },
+            None => match repo.head_commit() {
+                Ok(commit) => commit,
+                Err(_) => return Ok(ApiError::not_found(format!("{} side: {} has no commits yet", side, repo.head_ref())).into_response()),
+            },
+        };
+        let tree = commit.tree()?;
+        let blob = match tree.get_path(std::path::Path::new(path)) {
+            Ok(entry) => repo.find_blob(entry.id()),
+            Err(e) => Err(e),
+        };
+        match blob {
+            Ok(blob) => blobs.push(blob),
+            Err(_) => return Ok(ApiError::not_found(format!("{} side: {} does not exist at {}", side, path, rev.unwrap_or("HEAD"))).into_response()),
+        }
     }
 
-    #[derive(Clone)]
-    pub struct AppState {
-        pub repo: Arc<Mutex<Repository>>,
-        pub cached_entries: Arc<Mutex<Cached<Vec<ListEntry>>>>,
+    let mut patch = git2::Patch::from_blobs(&blobs[0], Some(std::path::Path::new(path)), &blobs[1], Some(std::path::Path::new(path)), None)?;
+    let buf = patch.to_buf()?;
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        Vec::from(&*buf),
+    ).into_response())
+}
+
+/// Replies with the frontmatter of the note in HEAD without its body, which is empty if there is none.
+fn note_meta(state: &AppState, repo: &Repo, path: &str) -> Result<Response, GitError> {
+    let Some(blob_id) = head_blob_id(state, repo, path)? else {
+        return Ok(ApiError::not_found(format!("{} not found", path)).into_response());
+    };
+    let blob = repo.find_blob(blob_id)?;
+    let metadata = std::str::from_utf8(blob.content())
+        .ok()
+        .and_then(parse_front_matter)
+        .map_or_else(|| Metadata::Mapping(Default::default()), |(metadata, _)| metadata);
+    Ok(Json(metadata).into_response())
+}
+
+/// Serializes the frontmatter of a markdown note as JSON for the `X-Note-Metadata` header.
+/// Returns `None` if there is no frontmatter or it is too large for a header.
+fn metadata_header(path: &str, content: &[u8]) -> Option<HeaderValue> {
+    const MAX_LENGTH: usize = 8 * 1024;
+
+    if guess_mime_type(path) != "text/markdown" {
+        return None;
     }
+    let (metadata, _) = parse_front_matter(std::str::from_utf8(content).ok()?)?;
+    let json = serde_json::to_string(&metadata).ok()?;
 
-    impl AppState {
-        pub fn new(repo: Repository) -> AppState {
-            let cache = match File::open("cache.msgpack") {
-                Ok(file) => {
-                    if let Ok(cache) = rmp_serde::from_read::<_, EntriesCache>(file) {
-                        Cached::Computed {
-                            commit_id: Oid::from_str(&cache.commit_id).unwrap(),
-                            data: cache.entries,
-                        }
-                    }
-                    else {
-                        Cached::None
-                    }
-                },
-                Err(_) => {
-                    Cached::None
-                },
-            };
-            AppState {
-                repo: Arc::new(Mutex::new(repo)),
-                cached_entries: Arc::new(Mutex::new(cache)),
+    // Header values are limited to ASCII, and other characters only appear in strings where they can be escaped
+    let mut ascii = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            ascii.push(c);
+        }
+        else {
+            for unit in c.encode_utf16(&mut [0; 2]) {
+                ascii.push_str(&format!("\\u{:04x}", unit));
             }
         }
     }
+    if ascii.len() > MAX_LENGTH {
+        return None;
+    }
+    HeaderValue::from_str(&ascii).ok()
+}
 
-    #[derive(Debug, Deserialize, Serialize, Clone)]
-    pub struct Login {
-        pub user: String,
-        pub password: String,
+/// Tells the commit which last changed each line of the note in HEAD.
+fn blame_note(repo: &Repo, path: &str) -> Result<Response, GitError> {
+    let Ok(head_commit) = repo.head_commit() else {
+        return Ok(ApiError::not_found(format!("{} not found", path)).into_response());
+    };
+    let blob = match head_commit.tree()?.get_path(std::path::Path::new(path)) {
+        Ok(entry) if entry.kind() == Some(git2::ObjectType::Blob) => repo.find_blob(entry.id())?,
+        _ => return Ok(ApiError::not_found(format!("{} not found", path)).into_response()),
+    };
+    if blob.is_binary() {
+        return Ok(ApiError::bad_request(format!("{} is a binary file", path)).into_response());
     }
 
-    #[derive(Debug, Deserialize, Serialize, Clone)]
-    pub enum NoteSave {
-        Save {
-            content: String,
-            message: String,
-        },
-        Rename {
-            from: String,
+    let mut opts = git2::BlameOptions::new();
+    opts.newest_commit(head_commit.id());
+    let blame = repo.blame_file(std::path::Path::new(path), Some(&mut opts))?;
+
+    // Hunks are in order and cover every line
+    let mut lines = Vec::new();
+    for hunk in blame.iter() {
+        let signature = hunk.final_signature();
+        let when = signature.when();
+        let tz = FixedOffset::east_opt(when.offset_minutes() * 60).unwrap();
+        let line = BlameLine {
+            commit_id: hunk.final_commit_id().to_string(),
+            author: signature.name().unwrap_or_default().to_owned(),
+            email: signature.email().unwrap_or_default().to_owned(),
+            time: tz.timestamp_opt(when.seconds(), 0).unwrap(),
+        };
+        lines.extend(std::iter::repeat_n(line, hunk.lines_in_hunk()));
+    }
+
+    Ok(Json(lines).into_response())
+}
+
+async fn put_notes(
+    State(state): State<Arc<AppState>>,
+    claims: Option<Extension<Claims>>,
+    ApiJson(batch): ApiJson<NoteBatch>,
+) -> Result<Response, GitError> {
+    debug!("put_notes");
+
+    // Validate everything up front so that nothing is committed on failure
+    if batch.notes.is_empty() {
+        return Ok(ApiError::bad_request("no notes given").into_response());
+    }
+    for note in &batch.notes {
+        if let Err(e) = validate_path(&note.path) {
+            return Ok(e.into_response());
+        }
+    }
+
+    commit_then_reply(&state, |repo| {
+        let (head_commit, mut index) = head_index(repo)?;
+        for note in &batch.notes {
+            let blob_oid = repo.blob(note.content.as_bytes()).map_err(|e| GitError(format!("failed to write blob: {}", e.message())))?;
+            let entry = blob_entry(&note.path, blob_oid, 0o100644);
+            if let Err(e) = check_file_dir_conflict(&index, &note.path) {
+                return Ok(Err(e.into_response()));
+            }
+            index.add(&entry)?;
+        }
+
+        let commit_id = commit_index(repo, index, head_commit, &batch.message, claims.as_deref())?;
+        let paths: Vec<String> = batch.notes.into_iter().map(|note| note.path).collect();
+        Ok(Ok((commit_id, paths.clone(), BatchCommitted { commit: commit_id.to_string(), paths })))
+    }).await
+}
+
+async fn head_notes_path(
+    RepoPath(path): RepoPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, GitError> {
+    debug!("head_notes_path");
+
+    let repo = state.repo.read().await?;
+    if let Some(blob_id) = head_blob_id(&state, &repo, &path)? {
+        let modified = last_modified(&state, &repo, &path)?;
+        let blob = repo.find_blob(blob_id)?;
+        let mut headers = match note_headers(&headers, &path, &blob, modified) {
+            Ok(headers) => headers,
+            Err(validators) => return Ok((StatusCode::NOT_MODIFIED, validators).into_response()),
+        };
+        headers.insert(header::CONTENT_TYPE, guess_mime_type(&path).parse().unwrap());
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(blob.size()));
+        Ok(headers.into_response())
+    }
+    else {
+        Ok(ApiError::not_found(format!("{} not found", path)).into_response())
+    }
+}
+
+/// Makes the headers replied alike to GET and HEAD for the note,
+/// or fails with those to reply 304 Not Modified with if the client has it already.
+fn note_headers(request: &HeaderMap, path: &str, blob: &git2::Blob, modified: Option<DateTime<FixedOffset>>) -> Result<HeaderMap, HeaderMap> {
+    // Blob ids are content hashes, so they can serve as ETags, if weak ones as the note may be
+    // sent compressed or as JSON
+    let etag = weak_entity_tag(blob.id());
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ETAG, etag.clone());
+    headers.insert(header::VARY, HeaderValue::from_static("accept"));
+    if let Some(modified) = modified {
+        headers.insert(header::LAST_MODIFIED, http_date(&modified));
+    }
+    let not_modified = if request.contains_key(header::IF_NONE_MATCH) {
+        etag_matches(request, &etag)
+    }
+    else {
+        modified.is_some_and(|modified| modified_since(request, &modified) == Some(false))
+    };
+    if not_modified {
+        return Err(headers);
+    }
+    // Saves clients a request to /notes for the metadata
+    if let Some(metadata) = metadata_header(path, blob.content()) {
+        headers.insert(HeaderName::from_static("x-note-metadata"), metadata);
+    }
+    Ok(headers)
+}
+
+async fn put_notes_path(
+    RepoPath(path): RepoPath,
+    State(state): State<Arc<AppState>>,
+    claims: Option<Extension<Claims>>,
+    ApiJson(note_save): ApiJson<NoteSave>,
+) -> Result<Response, GitError> {
+    debug!("put_notes_path");
+    debug!("{:?}", note_save);
+
+    commit_then_reply(&state, |repo| {
+        let mut moved = None;
+        let mut changed = vec![path.clone()];
+        let (head_commit, mut index) = head_index(repo)?;
+
+        let message = match note_save {
+            NoteSave::Save { content, message, base_commit, encoding } => {
+                // Refuse to overwrite changes made since the client's base commit
+                if let Some(base_commit) = base_commit {
+                    let head = head_commit.as_ref().map(|commit| commit.id().to_string()).unwrap_or_default();
+                    if base_commit != head {
+                        return Ok(Err(Conflict { head }.into_response()));
+                    }
+                }
+
+                let content = match encoding {
+                    ContentEncoding::Utf8 => content.into_bytes(),
+                    ContentEncoding::Base64 => match base64::engine::general_purpose::STANDARD.decode(&content) {
+                        Ok(content) => content,
+                        Err(e) => return Ok(Err(ApiError::bad_request(format!("invalid base64 content: {}", e)).into_response())),
+                    },
+                };
+                let blob_oid = repo.blob(&content).map_err(|e| GitError(format!("failed to write blob: {}", e.message())))?;
+                let entry = blob_entry(&path, blob_oid, 0o100644);
+                if let Err(e) = check_file_dir_conflict(&index, &path) {
+                    return Ok(Err(e.into_response()));
+                }
+                // The index is read from HEAD, so it tells whether the note exists there
+                let exists = index.get_path(std::path::Path::new(&path), 0).is_some();
+                index.add(&entry)?;
+                message.unwrap_or_else(|| format!("{} {}", if exists { "Update" } else { "Create" }, &path))
+            },
+            NoteSave::Rename { from } => {
+                if let Err(e) = validate_path(&from) {
+                    return Ok(Err(e.into_response()));
+                }
+                let found = index.get_path(std::path::Path::new(&from), 0);
+                if let Some(mut entry) = found {
+                    index.remove(from.as_ref(), 0)?;
+
+                    entry.path = path.as_bytes().into();
+                    if let Err(e) = check_file_dir_conflict(&index, &path) {
+                        return Ok(Err(e.into_response()));
+                    }
+                    index.add(&entry)?;
+                    changed.push(from.clone());
+                    format!("Rename {} to {}", &from, &path)
+                }
+                else {
+                    return Ok(Err(ApiError::not_found(format!("{} not found", from)).into_response()));
+                }
+            },
+            NoteSave::RenameDir { from, overwrite } => {
+                if let Err(e) = validate_path(&from) {
+                    return Ok(Err(e.into_response()));
+                }
+                let prefix = format!("{}/", from);
+                if path == from || path.starts_with(&prefix) {
+                    return Ok(Err(ApiError::bad_request(format!("cannot move {} into itself", from)).into_response()));
+                }
+
+                // Move every file under the directory at once
+                let entries: Vec<IndexEntry> = index
+                    .iter()
+                    .filter(|entry| entry.path.starts_with(prefix.as_bytes()))
+                    .collect();
+                if entries.is_empty() {
+                    return Ok(Err(ApiError::not_found(format!("{} is not a directory", from)).into_response()));
+                }
+                index.remove_dir(std::path::Path::new(&from), 0)?;
+                let count = entries.len();
+                for mut entry in entries {
+                    let dest = match std::str::from_utf8(&entry.path[prefix.len()..]) {
+                        Ok(rest) => format!("{}/{}", path, rest),
+                        Err(_) => return Ok(Err(ApiError::bad_request(format!("{} contains a file whose name is not valid UTF-8", from)).into_response())),
+                    };
+                    if !overwrite && index.get_path(std::path::Path::new(&dest), 0).is_some() {
+                        return Ok(Err(ApiError::new(StatusCode::CONFLICT, "conflict", format!("{} already exists", dest)).into_response()));
+                    }
+                    if let Err(e) = check_file_dir_conflict(&index, &dest) {
+                        return Ok(Err(e.into_response()));
+                    }
+                    entry.path = dest.into_bytes();
+                    index.add(&entry)?;
+                }
+                moved = Some(count);
+                changed.push(from.clone());
+                format!("Move {} to {}", &from, &path)
+            },
+            NoteSave::Restore { rev } => {
+                let commit = match find_commit(repo, &rev) {
+                    Ok(commit) => commit,
+                    Err(e) => return Ok(Err(e.into_response())),
+                };
+                match restore_entry(&mut index, &commit, &path)? {
+                    Ok(true) => (),
+                    Ok(false) => return Ok(Err(ApiError::not_found(format!("{} not found at {}", path, rev)).into_response())),
+                    Err(e) => return Ok(Err(e.into_response())),
+                }
+
+                let short_id = commit.as_object().short_id()?;
+                format!("Restore {} to {}", &path, short_id.as_str().unwrap_or_default())
+            },
+        };
+
+        let commit_id = commit_index(repo, index, head_commit, &message, claims.as_deref())?;
+        Ok(Ok((commit_id, changed, Committed { commit: commit_id.to_string(), path, moved })))
+    }).await
+}
+
+/// Handles `POST /notes/<path>/<action>`, which cannot be routed as such since a wildcard has to come last.
+async fn post_notes_path(
+    RepoPath(path): RepoPath,
+    State(state): State<Arc<AppState>>,
+    claims: Option<Extension<Claims>>,
+    request: Request<Body>,
+) -> Result<Response, GitError> {
+    debug!("post_notes_path");
+
+    if let Some(path) = path.strip_suffix("/revert") {
+        let ApiJson(revert) = match ApiJson::<RevertNote>::from_request(request, &state).await {
+            Ok(revert) => revert,
+            Err(e) => return Ok(e.into_response()),
+        };
+        revert_note(&state, path.to_owned(), claims.as_deref(), revert).await
+    }
+    else if let Some(path) = path.strip_suffix("/restore") {
+        restore_note(&state, path.to_owned(), claims.as_deref()).await
+    }
+    else {
+        Ok(ApiError::not_found(format!("no such endpoint: POST /notes/{}", path)).into_response())
+    }
+}
+
+/// Brings the note back to how it was at the commit, deleting it if it did not exist then.
+async fn revert_note(state: &Arc<AppState>, path: String, claims: Option<&Claims>, revert: RevertNote) -> Result<Response, GitError> {
+    commit_then_reply(state, |repo| {
+        let commit = match find_commit(repo, &revert.commit_id) {
+            Ok(commit) => commit,
+            Err(e) => return Ok(Err(e.into_response())),
+        };
+        let (head_commit, mut index) = head_index(repo)?;
+
+        match restore_entry(&mut index, &commit, &path)? {
+            Ok(true) => (),
+            Err(e) => return Ok(Err(e.into_response())),
+            // The file did not exist at the commit
+            Ok(false) => {
+                if index.get_path(std::path::Path::new(&path), 0).is_none() {
+                    return Ok(Err(ApiError::not_found(format!("{} exists neither at {} nor now", path, revert.commit_id)).into_response()));
+                }
+                index.remove_path(std::path::Path::new(&path))?;
+            },
+        }
+
+        let commit_id = commit_index(repo, index, head_commit, &revert.message, claims)?;
+        Ok(Ok((commit_id, vec![path.clone()], Committed { commit: commit_id.to_string(), path, moved: None })))
+    }).await
+}
+
+/// Moves the note back from the trash.
+async fn restore_note(state: &Arc<AppState>, path: String, claims: Option<&Claims>) -> Result<Response, GitError> {
+    let Some(trash_dir) = &state.config.trash_dir else {
+        return Ok(ApiError::not_found("the trash is disabled").into_response());
+    };
+
+    let trash_path = format!("{}/{}", trash_dir, path);
+    commit_then_reply(state, |repo| {
+        let commit_id = match move_entry(repo, &trash_path, &path, false, &format!("Restore {} from trash", path), claims)? {
+            Ok(commit_id) => commit_id,
+            Err(e) => return Ok(Err(e.into_response())),
+        };
+        Ok(Ok((commit_id, vec![path.clone(), trash_path], Committed { commit: commit_id.to_string(), path, moved: None })))
+    }).await
+}
+
+/// Moves the file in a single commit.
+/// Whatever is at the destination is replaced only if `replace` is set.
+fn move_entry(repo: &Repo, from: &str, to: &str, replace: bool, message: &str, claims: Option<&Claims>) -> Result<Result<Oid, ApiError>, GitError> {
+    let (head_commit, mut index) = head_index(repo)?;
+
+    if let Err(e) = move_index_entry(&mut index, from, to, replace)? {
+        return Ok(Err(e));
+    }
+
+    let commit_id = commit_index(repo, index, head_commit, message, claims)?;
+    Ok(Ok(commit_id))
+}
+
+/// Moves the file within the index, replacing whatever is at the destination only if `replace` is set.
+fn move_index_entry(index: &mut Index, from: &str, to: &str, replace: bool) -> Result<Result<(), ApiError>, GitError> {
+    let Some(mut entry) = index.get_path(std::path::Path::new(from), 0) else {
+        return Ok(Err(ApiError::not_found(format!("{} not found", from))));
+    };
+    if !replace && index.get_path(std::path::Path::new(to), 0).is_some() {
+        return Ok(Err(ApiError::new(StatusCode::CONFLICT, "conflict", format!("{} already exists", to))));
+    }
+    index.remove_path(std::path::Path::new(from))?;
+    if let Err(e) = check_file_dir_conflict(index, to) {
+        return Ok(Err(e));
+    }
+    entry.path = to.as_bytes().into();
+    index.add(&entry)?;
+    Ok(Ok(()))
+}
+
+/// Makes an index entry for the blob at the path.
+/// Stat data is left empty as it is only used to detect changes in a working directory.
+fn blob_entry(path: &str, id: Oid, mode: u32) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id,
+        flags: 0,
+        flags_extended: 0,
+        path: path.as_bytes().into(),
+    }
+}
+
+/// Looks up a commit by its id as given by a client.
+fn find_commit<'r>(repo: &'r Repo, id: &str) -> Result<git2::Commit<'r>, ApiError> {
+    match Oid::from_str(id) {
+        Ok(oid) => repo.find_commit(oid).map_err(|_| ApiError::not_found(format!("commit {} not found", id))),
+        Err(_) => Err(ApiError::bad_request(format!("invalid commit id: {}", id))),
+    }
+}
+
+/// Puts the file at the path back into the index as it was at the commit.
+/// Tells whether the file existed then, leaving the index as is if it did not.
+fn restore_entry(index: &mut Index, commit: &git2::Commit, path: &str) -> Result<Result<bool, ApiError>, GitError> {
+    let old_entry = match commit.tree()?.get_path(std::path::Path::new(path)) {
+        Ok(old_entry) => old_entry,
+        Err(_) => return Ok(Ok(false)),
+    };
+    if let Err(e) = check_file_dir_conflict(index, path) {
+        return Ok(Err(e));
+    }
+    index.add(&blob_entry(path, old_entry.id(), old_entry.filemode() as u32))?;
+    Ok(Ok(true))
+}
+
+/// Loads the tree of HEAD into an index to be modified and committed.
+/// Before the first commit there is no HEAD and the index starts out empty.
+fn head_index(repo: &Repo) -> Result<(Option<git2::Commit<'_>>, Index), GitError> {
+    let mut index = Index::new()?;
+    if repo.is_unborn() {
+        return Ok((None, index));
+    }
+
+    let head_commit = repo.head_commit().map_err(|e| GitError(format!("failed to resolve {}: {}", repo.head_ref(), e.message())))?;
+    index.read_tree(&head_commit.tree()?)?;
+
+    Ok((Some(head_commit), index))
+}
+
+/// Looks up the blob at the path directly in the tree, without loading the whole tree into an index.
+fn find_blob_id(tree: &git2::Tree, path: &str) -> Option<Oid> {
+    let entry = tree.get_path(std::path::Path::new(path)).ok()?;
+    match entry.kind() {
+        Some(git2::ObjectType::Blob) => Some(entry.id()),
+        _ => None,
+    }
+}
+
+/// Looks up the blob at the path in HEAD through a map from paths to blobs cached for the commit.
+fn head_blob_id(state: &AppState, repo: &Repo, path: &str) -> Result<Option<Oid>, GitError> {
+    let mut cached_blob_ids = state.cached_blob_ids.lock().unwrap();
+    if let Cache::Valid(blob_ids) = cached_blob_ids.get(repo) {
+        return Ok(blob_ids.get(path).copied());
+    }
+
+    // Rebuild the map for the current HEAD
+    if repo.is_unborn() {
+        return Ok(None);
+    }
+    let head_commit = repo.head_commit()?;
+    let mut index = Index::new()?;
+    index.read_tree(&head_commit.tree()?)?;
+    let blob_ids: HashMap<String, Oid> = index
+        .iter()
+        // Skip submodules
+        .filter(|entry| entry.mode != 0o160000)
+        .filter_map(|entry| String::from_utf8(entry.path).ok().map(|path| (path, entry.id)))
+        .collect();
+    let found = blob_ids.get(path).copied();
+    *cached_blob_ids = Cached::Computed {
+        commit_id: head_commit.id(),
+        data: blob_ids,
+    };
+    Ok(found)
+}
+
+/// Commits the index on top of the head commit, or as the first commit if there is none.
+fn commit_index(repo: &Repo, mut index: Index, head_commit: Option<git2::Commit>, message: &str, claims: Option<&Claims>) -> Result<Oid, GitError> {
+    let tree_oid = index.write_tree_to(repo)?;
+    let tree = repo.find_tree(tree_oid)?;
+
+    let author = signature_for(repo, claims)?;
+    let committer = repo.signature()?;
+    let commit_id = repo.commit(
+        Some(repo.head_ref()),
+        &author,
+        &committer,
+        message,
+        &tree,
+        &head_commit.iter().collect::<Vec<_>>(),
+    )?;
+    metrics::counter!("moried_commits_total").increment(1);
+    Ok(commit_id)
+}
+
+/// Commits with the repository locked for writing, and then replies with the result of the commit after pushing it.
+/// `commit` gives the id of the commit, the paths it changed and the result, or else the response to reply without committing.
+/// The repository is released before pushing, so that the push holds up no other request.
+async fn commit_then_reply<T: serde::Serialize>(
+    state: &Arc<AppState>,
+    commit: impl FnOnce(&Repo) -> Result<Result<(Oid, Vec<String>, T), Response>, GitError>,
+) -> Result<Response, GitError> {
+    let committed = {
+        let repo = state.repo.write().await?;
+        commit(&repo)?
+    };
+    match committed {
+        Ok((commit_id, paths, result)) => {
+            notify(state, commit_id, paths);
+            Ok(reply_pushed(state, result).await)
+        },
+        Err(response) => Ok(response),
+    }
+}
+
+/// Tells the subscribers of `/events` about the commit.
+fn notify(state: &AppState, commit_id: Oid, paths: Vec<String>) {
+    // Fails only when nobody is subscribed
+    state.events.send(RepoEvent { commit: commit_id.to_string(), paths }).ok();
+}
+
+/// Streams an event for each commit made from now on as server-sent events.
+async fn get_events(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl futures::Stream<Item = Result<Event, axum::Error>>> {
+    debug!("get_events");
+
+    let events = futures::stream::unfold(state.events.subscribe(), |mut rx| async move {
+        use tokio::sync::broadcast::error::RecvError;
+
+        let event = match rx.recv().await {
+            Ok(event) => Event::default().event("commit").json_data(event),
+            // The oldest events have been dropped for a slow client, which should reload everything
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("dropped {} events for a slow subscriber", skipped);
+                Ok(Event::default().event("lagged").data(skipped.to_string()))
+            },
+            Err(RecvError::Closed) => return None,
+        };
+        Some((event, rx))
+    });
+    // Keep-alives would hold the connection open forever, so end the stream on shutdown
+    let events = events.take_until(state.shutdown.clone().cancelled_owned());
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// How long to wait for a push or a fetch, which may hang on an unresponsive remote.
+const REMOTE_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// Replies to a write with its result, after pushing the commit to MORIED_PUSH_REMOTE if set.
+/// The result comes together with the status of the push, which is `null` without a remote.
+/// The commit has already succeeded locally, so a failed push does not fail the request.
+async fn reply_pushed<T: serde::Serialize>(state: &Arc<AppState>, result: T) -> Response {
+    let Some(remote) = state.config.push_remote.clone() else {
+        return Json(Pushed { result, push: None }).into_response();
+    };
+
+    let pushing_state = Arc::clone(state);
+    let pushing_remote = remote.clone();
+    let pushed = tokio::time::timeout(REMOTE_TIMEOUT, async move {
+        // One push at a time, each of which sends the latest commit.
+        // The lock is held until the push is over, even if the reply has been sent meanwhile
+        let guard = Arc::clone(&pushing_state.push_lock).lock_owned().await;
+        tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            let repo = pushing_state.repo.open_unlocked()?;
+            push_branch(&repo, &pushing_state.config, &pushing_remote)
+        }).await.map_err(|e| GitError(e.to_string()))?
+    }).await;
+
+    let error = match pushed {
+        Ok(Ok(refname)) => {
+            info!("pushed {} to {}", refname, remote);
+            None
         },
+        Ok(Err(GitError(message))) => Some(message),
+        Err(_) => Some(format!("timed out after {} seconds", REMOTE_TIMEOUT.as_secs())),
+    };
+    if let Some(message) = &error {
+        warn!("failed to push to {}: {}", remote, message);
+    }
+    Json(Pushed {
+        result,
+        push: Some(PushStatus {
+            remote,
+            ok: error.is_none(),
+            error,
+        }),
+    }).into_response()
+}
+
+/// Pushes the served branch to the remote and returns the name of the branch.
+fn push_branch(repo: &Repo, config: &Config, remote: &str) -> Result<String, GitError> {
+    // Push the branch HEAD points to, rather than HEAD itself
+    let refname = branch_name(repo)?;
+    let mut remote = repo.find_remote(remote)?;
+
+    let mut rejection = None;
+    let mut callbacks = remote_callbacks(config);
+    callbacks.push_update_reference(|refname, status| {
+        if let Some(status) = status {
+            rejection = Some(format!("{} was rejected: {}", refname, status));
+        }
+        Ok(())
+    });
+    let mut options = git2::PushOptions::new();
+    options.remote_callbacks(callbacks);
+    remote.push(&[format!("{0}:{0}", refname)], Some(&mut options))?;
+    drop(options);
+
+    match rejection {
+        Some(message) => Err(GitError(message)),
+        None => Ok(refname),
+    }
+}
+
+/// Returns the full name of the served branch, e.g. `refs/heads/main`.
+fn branch_name(repo: &Repo) -> Result<String, GitError> {
+    let branch = repo.branch_ref()?;
+    branch.name()
+        .map(str::to_owned)
+        .ok_or_else(|| GitError(format!("{} is not valid UTF-8", repo.head_ref())))
+}
+
+/// Sets up callbacks to authenticate to the remote.
+fn remote_callbacks(config: &Config) -> git2::RemoteCallbacks<'_> {
+    // libgit2 asks again and again as long as the credentials are rejected
+    let mut attempts = 0;
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(move |_url, username, allowed| {
+        attempts += 1;
+        if attempts > 3 {
+            return Err(git2::Error::from_str("authentication failed"));
+        }
+        remote_credentials(config, username.unwrap_or("git"), allowed)
+    });
+    callbacks
+}
+
+/// Picks credentials for the remote from MORIED_SSH_KEY or MORIED_PUSH_TOKEN.
+fn remote_credentials(config: &Config, username: &str, allowed: git2::CredentialType) -> Result<git2::Cred, git2::Error> {
+    if allowed.contains(git2::CredentialType::USERNAME) {
+        return git2::Cred::username(username);
+    }
+    if allowed.contains(git2::CredentialType::SSH_KEY) {
+        if let Some(key) = &config.ssh_key {
+            return git2::Cred::ssh_key(username, None, key, None);
+        }
+    }
+    if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+        if let Some(token) = &config.push_token {
+            return git2::Cred::userpass_plaintext(username, token);
+        }
+    }
+    Err(git2::Error::from_str("no credentials are configured for the remote"))
+}
+
+/// Drops the caches so that they are rebuilt from scratch, e.g. after they got out of sync with the repository somehow.
+async fn post_admin_cache_clear(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<CacheCleared>, GitError> {
+    debug!("post_admin_cache_clear");
+
+    let repo = state.repo.read().await?;
+    // There is no head before the first commit
+    let head = if repo.is_unborn() {
+        None
+    }
+    else {
+        Some(repo.head_commit()?.id().to_string())
+    };
+    *state.cached_entries.lock().await = Cached::None;
+    *state.cached_blob_ids.lock().unwrap() = Cached::None;
+    *state.cached_last_modified.lock().unwrap() = Cached::None;
+    info!("cleared the caches at {:?}", head);
+
+    Ok(Json(CacheCleared { head }))
+}
+
+/// Fetches the served branch from the remote and fast-forwards it if possible.
+async fn post_sync(
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, GitError> {
+    debug!("post_sync");
+
+    // The route exists only if a remote is configured
+    let Some(remote) = state.config.sync_remote().map(str::to_owned) else {
+        return Ok(ApiError::not_found("no remote is configured").into_response());
+    };
+
+    match pull(&state, &remote).await? {
+        FastForward::UpToDate(head) => Ok(Json(Synced { head: head.to_string(), updated: false }).into_response()),
+        FastForward::Updated(head) => Ok(Json(Synced { head: head.to_string(), updated: true }).into_response()),
+        FastForward::Diverged { branch, local, remote: remote_id } => Ok(ApiError::new(
+            StatusCode::CONFLICT,
+            "conflict",
+            format!("{} has diverged from {}: local {}, remote {}", branch, remote, local, remote_id),
+        ).into_response()),
+    }
+}
+
+/// Pulls from MORIED_PULL_REMOTE every MORIED_PULL_INTERVAL.
+/// A branch which has diverged from the remote is left as it is until someone merges them.
+async fn pull_periodically(state: Arc<AppState>, remote: String) {
+    let mut interval = tokio::time::interval(state.config.pull_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match pull(&state, &remote).await {
+            Ok(FastForward::UpToDate(_) | FastForward::Updated(_)) => {},
+            Ok(FastForward::Diverged { branch, local, remote: remote_id }) => {
+                warn!("skipped pulling from {}: {} has diverged: local {}, remote {}", remote, branch, local, remote_id);
+            },
+            Err(GitError(message)) => warn!("failed to pull from {}: {}", remote, message),
+        }
+    }
+}
+
+/// Fetches the served branch from the remote and fast-forwards it if possible.
+async fn pull(state: &Arc<AppState>, remote: &str) -> Result<FastForward, GitError> {
+    // Fetching does not touch the served branch, so other requests can go on meanwhile
+    let fetching_state = Arc::clone(state);
+    let fetching_remote = remote.to_owned();
+    let fetching = tokio::task::spawn_blocking(move || {
+        let repo = fetching_state.repo.open_unlocked()?;
+        fetch_branch(&repo, &fetching_state.config, &fetching_remote)
+    });
+    let fetched = tokio::time::timeout(REMOTE_TIMEOUT, fetching)
+        .await
+        .map_err(|_| GitError(format!("timed out fetching from {} after {} seconds", remote, REMOTE_TIMEOUT.as_secs())))?
+        .map_err(|e| GitError(e.to_string()))??;
+
+    // The caches are keyed by the head commit, so moving the branch invalidates them
+    let repo = state.repo.write().await?;
+    let result = fast_forward(&repo, fetched)?;
+    if let FastForward::Updated(head) = &result {
+        info!("fast-forwarded to {} from {}", head, remote);
+    }
+    Ok(result)
+}
+
+/// Fetches the served branch from the remote into its remote-tracking branch and returns the fetched commit.
+fn fetch_branch(repo: &Repo, config: &Config, remote: &str) -> Result<Oid, GitError> {
+    let refname = branch_name(repo)?;
+    let tracking = format!("refs/remotes/{}/{}", remote, refname.strip_prefix("refs/heads/").unwrap_or(&refname));
+    let mut remote = repo.find_remote(remote)?;
+
+    let mut options = git2::FetchOptions::new();
+    options.remote_callbacks(remote_callbacks(config));
+    remote.fetch(&[format!("+{}:{}", refname, tracking)], Some(&mut options), None)?;
+
+    Ok(repo.refname_to_id(&tracking)?)
+}
+
+enum FastForward {
+    UpToDate(Oid),
+    Updated(Oid),
+    Diverged { branch: String, local: Oid, remote: Oid },
+}
+
+/// Moves the served branch to the commit if it is a descendant of the current one.
+fn fast_forward(repo: &Repo, target: Oid) -> Result<FastForward, GitError> {
+    let mut branch = repo.branch_ref()?;
+    let local = branch.peel_to_commit()?.id();
+
+    let annotated = repo.find_annotated_commit(target)?;
+    let (analysis, _) = repo.merge_analysis_for_ref(&branch, &[&annotated])?;
+    if analysis.is_up_to_date() {
+        Ok(FastForward::UpToDate(local))
+    }
+    else if analysis.is_fast_forward() {
+        branch.set_target(target, &format!("moried: fast-forward to {}", target))?;
+        Ok(FastForward::Updated(target))
+    }
+    else {
+        Ok(FastForward::Diverged { branch: branch_name(repo)?, local, remote: target })
+    }
+}
+
+/// Removes the file at the path from HEAD and commits it.
+/// Returns the commit, or `None` if there is no such file.
+fn delete_entry(repo: &Repo, path: &str, message: &str, claims: Option<&Claims>) -> Result<Option<Oid>, GitError> {
+    let (head_commit, mut index) = head_index(repo)?;
+
+    if index.get_path(std::path::Path::new(path), 0).is_none() {
+        return Ok(None);
+    }
+    index.remove_path(std::path::Path::new(path))?;
+
+    let commit_id = commit_index(repo, index, head_commit, message, claims)?;
+    Ok(Some(commit_id))
+}
+
+/// Deletes several files in a single commit, reporting those which do not exist instead of failing.
+async fn post_notes_bulk_delete(
+    State(state): State<Arc<AppState>>,
+    claims: Option<Extension<Claims>>,
+    ApiJson(mut bulk): ApiJson<BulkDelete>,
+) -> Result<Response, GitError> {
+    debug!("post_notes_bulk_delete");
+
+    for path in &bulk.paths {
+        if let Err(e) = validate_path(path) {
+            return Ok(e.into_response());
+        }
+    }
+    // A path given twice is deleted once, not reported missing the second time
+    let mut seen = HashSet::new();
+    bulk.paths.retain(|path| seen.insert(path.clone()));
+
+    commit_then_reply(&state, |repo| {
+        let mut deleted = Vec::new();
+        let mut not_found = Vec::new();
+        let mut changed = Vec::new();
+        let (head_commit, mut index) = head_index(repo)?;
+        for path in bulk.paths {
+            if index.get_path(std::path::Path::new(&path), 0).is_none() {
+                not_found.push(path);
+                continue;
+            }
+            match &state.config.trash_dir {
+                // Notes already in the trash are deleted for good, as by DELETE /notes/<path>
+                Some(trash_dir) if !state.config.in_trash(std::path::Path::new(&path)) => {
+                    let trash_path = format!("{}/{}", trash_dir, path);
+                    if let Err(e) = move_index_entry(&mut index, &path, &trash_path, true)? {
+                        return Ok(Err(e.into_response()));
+                    }
+                    changed.push(trash_path);
+                },
+                _ => index.remove_path(std::path::Path::new(&path))?,
+            }
+            changed.push(path.clone());
+            deleted.push(path);
+        }
+
+        // Nothing to commit, nor to push
+        if deleted.is_empty() {
+            return Ok(Err(Json(Pushed { result: BulkDeleted { commit: None, deleted, not_found }, push: None }).into_response()));
+        }
+        let commit_id = commit_index(repo, index, head_commit, &bulk.message, claims.as_deref())?;
+        Ok(Ok((commit_id, changed, BulkDeleted { commit: Some(commit_id.to_string()), deleted, not_found })))
+    }).await
+}
+
+async fn delete_notes_path(
+    RepoPath(path): RepoPath,
+    State(state): State<Arc<AppState>>,
+    claims: Option<Extension<Claims>>,
+    ApiQuery(query): ApiQuery<DeleteQuery>,
+) -> Result<Response, GitError> {
+    debug!("delete_notes_path");
+
+    commit_then_reply(&state, |repo| {
+        let mut changed = vec![path.clone()];
+        let commit_id = match &state.config.trash_dir {
+            // Notes already in the trash are deleted for good
+            Some(trash_dir) if !query.purge && !state.config.in_trash(std::path::Path::new(&path)) => {
+                let trash_path = format!("{}/{}", trash_dir, path);
+                match move_entry(repo, &path, &trash_path, true, &format!("Move {} to trash", &path), claims.as_deref())? {
+                    Ok(commit_id) => {
+                        changed.push(trash_path);
+                        commit_id
+                    },
+                    Err(e) => return Ok(Err(e.into_response())),
+                }
+            },
+            _ => match delete_entry(repo, &path, &format!("Delete {}", &path), claims.as_deref())? {
+                Some(commit_id) => commit_id,
+                None => return Ok(Err(ApiError::not_found(format!("{} not found", path)).into_response())),
+            },
+        };
+        Ok(Ok((commit_id, changed, Committed { commit: commit_id.to_string(), path, moved: None })))
+    }).await
+}
+
+async fn get_tree(
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, GitError> {
+    debug!("get_tree");
+
+    let repo = state.repo.read().await?;
+    list_tree(&repo, "")
+}
+
+async fn get_tree_path(
+    RepoPath(path): RepoPath,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, GitError> {
+    debug!("get_tree_path");
+
+    let repo = state.repo.read().await?;
+    list_tree(&repo, &path)
+}
+
+/// Lists the immediate children of the directory in HEAD, or of the root if the path is empty.
+fn list_tree(repo: &Repo, path: &str) -> Result<Response, GitError> {
+    let head_tree = repo.head_tree()?;
+    let tree = if path.is_empty() {
+        head_tree
+    }
+    else {
+        match head_tree.get_path(std::path::Path::new(path)).ok().and_then(|entry| entry.to_object(repo).ok()?.into_tree().ok()) {
+            Some(tree) => tree,
+            None => return Ok(ApiError::not_found(format!("{} is not a directory", path)).into_response()),
+        }
+    };
+
+    let children: Vec<TreeEntry> = tree
+        .iter()
+        .filter_map(|entry| {
+            let kind = match entry.kind() {
+                Some(git2::ObjectType::Blob) => "blob",
+                Some(git2::ObjectType::Tree) => "tree",
+                // Skip submodules
+                _ => return None,
+            };
+            Some(TreeEntry {
+                name: String::from_utf8_lossy(entry.name_bytes()).into_owned(),
+                kind,
+                oid: entry.id().to_string(),
+            })
+        })
+        .collect();
+    Ok(Json(children).into_response())
+}
+
+async fn get_files(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<FileEntry>>, GitError> {
+    debug!("get_files");
+
+    let repo = state.repo.read().await?;
+    let head_tree = repo.head_tree()?;
+    let odb = repo.odb()?;
+
+    // Collect every blob except for notes, which are markdown files unless MORIED_NOTE_EXTENSIONS is set
+    let mut files = Vec::new();
+    let mut error = None;
+    head_tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+        if entry.kind() != Some(git2::ObjectType::Blob) {
+            return git2::TreeWalkResult::Ok;
+        }
+        let path = PathBuf::from(dir).join(OsStr::from_bytes(entry.name_bytes()));
+        let mime_type = guess_mime_type(&path);
+        let is_note = match state.config.note_extensions {
+            Some(_) => state.config.is_note(&path),
+            None => mime_type == "text/markdown",
+        };
+        if is_note {
+            return git2::TreeWalkResult::Ok;
+        }
+        match odb.read_header(entry.id()) {
+            Ok((size, _)) => {
+                files.push(FileEntry {
+                    path,
+                    size,
+                    mime_type,
+                });
+                git2::TreeWalkResult::Ok
+            },
+            Err(e) => {
+                error = Some(e);
+                git2::TreeWalkResult::Abort
+            },
+        }
+    })?;
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+
+    Ok(Json(files))
+}
+
+async fn get_files_path(
+    RepoPath(path): RepoPath,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Response {
+    debug!("get_files_path");
+
+    let found = {
+        let repo = match state.repo.read().await {
+            Ok(repo) => repo,
+            Err(e) => return e.into_response(),
+        };
+
+        let found = match head_blob_id(&state, &repo, &path) {
+            Ok(found) => found,
+            Err(e) => return e.into_response(),
+        };
+        match found {
+            // Read only the header of the object to know its size without loading the content
+            Some(blob_id) => match repo.odb().and_then(|odb| odb.read_header(blob_id)) {
+                Ok((size, _)) => Some((blob_id, size)),
+                Err(e) => return GitError::from(e).into_response(),
+            },
+            None => None,
+        }
+    };
+    if let Some((blob_id, size)) = found {
+        // Blob ids are content hashes, so they can serve as ETags
+        let etag = entity_tag(blob_id);
+        if etag_matches(&headers, &etag) {
+            return (StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response();
+        }
+        let git_dir = state.config.git_dir.clone();
+        // Guess the mime type
+        let mime_type = guess_mime_type(&path);
+        match requested_range(&headers, size) {
+            Some(Some(range)) => (
+                StatusCode::PARTIAL_CONTENT,
+                [
+                    (header::ETAG, etag),
+                    (header::CONTENT_TYPE, mime_type.parse().unwrap()),
+                    (header::CONTENT_LENGTH, HeaderValue::from(range.len())),
+                    (header::CONTENT_RANGE, format!("bytes {}-{}/{}", range.start, range.end - 1, size).parse().unwrap()),
+                    (header::ACCEPT_RANGES, HeaderValue::from_static("bytes")),
+                ],
+                stream_blob(git_dir, blob_id, range),
+            ).into_response(),
+            Some(None) => (
+                [(header::CONTENT_RANGE, format!("bytes */{}", size))],
+                ApiError::new(StatusCode::RANGE_NOT_SATISFIABLE, "range_not_satisfiable", format!("the range is out of {} bytes", size)),
+            ).into_response(),
+            None => (
+                [
+                    (header::ETAG, etag),
+                    (header::CONTENT_TYPE, mime_type.parse().unwrap()),
+                    (header::CONTENT_LENGTH, HeaderValue::from(size)),
+                    (header::ACCEPT_RANGES, HeaderValue::from_static("bytes")),
+                ],
+                stream_blob(git_dir, blob_id, 0..size),
+            ).into_response(),
+        }
+    }
+    else {
+        ApiError::not_found(format!("{} not found", path)).into_response()
+    }
+}
+
+/// Parses the `Range` header for a single byte range of an entity of the given size.
+///
+/// Returns `None` if the whole entity should be sent, which is also the case for multiple or
+/// malformed ranges, and `Some(None)` if the range cannot be satisfied.
+fn requested_range(headers: &HeaderMap, size: usize) -> Option<Option<std::ops::Range<usize>>> {
+    let spec = headers.get(header::RANGE)?.to_str().ok()?.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.trim().split_once('-')?;
+    if start.is_empty() {
+        // The last `end` bytes
+        let len = end.parse::<usize>().ok()?;
+        if len == 0 || size == 0 {
+            return Some(None);
+        }
+        return Some(Some(size.saturating_sub(len)..size));
+    }
+    let start = start.parse::<usize>().ok()?;
+    let end = if end.is_empty() {
+        usize::MAX
+    }
+    else {
+        end.parse::<usize>().ok()?
+    };
+    if end < start {
+        return None;
+    }
+    if start >= size {
+        return Some(None);
+    }
+    Some(Some(start..end.min(size - 1) + 1))
+}
+
+/// Streams the content of a blob in chunks rather than buffering it into a response at once.
+///
+/// The blob is read through a separate handle to the repository so that the repository lock is
+/// not held while the response is being sent.
+fn stream_blob(git_dir: PathBuf, blob_id: Oid, range: std::ops::Range<usize>) -> Body {
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, git2::Error>>(4);
+    tokio::task::spawn_blocking(move || {
+        let repo = match Repository::open(&git_dir) {
+            Ok(repo) => repo,
+            Err(e) => return tx.blocking_send(Err(e)).unwrap_or(()),
+        };
+        let blob = match repo.find_blob(blob_id) {
+            Ok(blob) => blob,
+            Err(e) => return tx.blocking_send(Err(e)).unwrap_or(()),
+        };
+        for chunk in blob.content()[range].chunks(CHUNK_SIZE) {
+            if tx.blocking_send(Ok(Bytes::copy_from_slice(chunk))).is_err() {
+                // The client has gone
+                break;
+            }
+        }
+    });
+
+    Body::from_stream(futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
+
+async fn get_git_blob(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Response, GitError> {
+    debug!("get_git_blob");
+
+    let blob_id = match parse_object_id(&id) {
+        Ok(blob_id) => blob_id,
+        Err(e) => return Ok(e.into_response()),
+    };
+    let repo = state.repo.read().await?;
+    let size = match repo.odb()?.read_header(blob_id) {
+        Ok((size, git2::ObjectType::Blob)) => size,
+        _ => return Ok(ApiError::not_found(format!("blob {} not found", id)).into_response()),
+    };
+    drop(repo);
+
+    // Objects never change, so the id is a strong ETag
+    let etag = entity_tag(blob_id);
+    if etag_matches(&headers, &etag) {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+    Ok((
+        [
+            (header::ETAG, etag),
+            (header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream")),
+            (header::CONTENT_LENGTH, HeaderValue::from(size)),
+        ],
+        stream_blob(state.config.git_dir.clone(), blob_id, 0..size),
+    ).into_response())
+}
+
+async fn get_git_commit(
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, GitError> {
+    debug!("get_git_commit");
+
+    let commit_id = match parse_object_id(&id) {
+        Ok(commit_id) => commit_id,
+        Err(e) => return Ok(e.into_response()),
+    };
+    let repo = state.repo.read().await?;
+    let Ok(commit) = repo.find_commit(commit_id) else {
+        return Ok(ApiError::not_found(format!("commit {} not found", id)).into_response());
+    };
+
+    let author = commit.author();
+    Ok(Json(GitCommit {
+        id: commit.id().to_string(),
+        tree: commit.tree_id().to_string(),
+        parents: commit.parent_ids().map(|parent_id| parent_id.to_string()).collect(),
+        author: GitSignature {
+            name: author.name().unwrap_or_default().to_owned(),
+            email: author.email().unwrap_or_default().to_owned(),
+            time: commit_time(&commit),
+        },
+        message: commit.message().unwrap_or_default().to_owned(),
+    }).into_response())
+}
+
+/// Parses a full object id, refusing abbreviated ones which `Oid::from_str` would pad with zeros.
+fn parse_object_id(id: &str) -> Result<Oid, ApiError> {
+    if id.len() == 40 && id.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Oid::from_str(id).map_err(|_| ApiError::bad_request(format!("invalid object id: {}", id)))
+    }
+    else {
+        Err(ApiError::bad_request(format!("invalid object id: {}", id)))
+    }
+}
+
+async fn get_export(
+    ApiQuery(query): ApiQuery<ExportQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, GitError> {
+    debug!("get_export");
+
+    // Resolve the snapshot to export, defaulting to HEAD
+    let repo = state.repo.read().await?;
+    let commit = match &query.rev {
+        Some(rev) => match find_commit(&repo, rev) {
+            Ok(commit) => Some(commit),
+            Err(e) => return Ok(e.into_response()),
+        },
+        None if repo.is_unborn() => None,
+        None => Some(repo.head_commit()?),
+    };
+    let (tree_id, time, filename) = match &commit {
+        Some(commit) => (
+            commit.tree_id(),
+            Some(commit_time(commit)),
+            format!("moried-{}.zip", &commit.id().to_string()[..7]),
+        ),
+        None => (repo.head_tree()?.id(), None, "moried.zip".to_owned()),
+    };
+    drop(commit);
+    drop(repo);
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, HeaderValue::from_static("application/zip")),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename).parse().unwrap()),
+        ],
+        stream_zip(state.config.git_dir.clone(), tree_id, time),
+    ).into_response())
+}
+
+/// Streams a zip archive of every file in the tree, dated at the given time.
+///
+/// Like `stream_blob`, the archive is written through a separate handle to the repository,
+/// and it is sent file by file as it is written rather than buffered as a whole.
+fn stream_zip(git_dir: PathBuf, tree_id: Oid, time: Option<DateTime<FixedOffset>>) -> Body {
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Bytes, std::io::Error>>(4);
+    tokio::task::spawn_blocking(move || {
+        let error_tx = tx.clone();
+        if let Err(e) = write_zip(&git_dir, tree_id, time, ZipChunks { tx, buf: Vec::new(), sent: 0, pos: 0 }) {
+            // Abort the response so that the client does not take a truncated archive as complete
+            error_tx.blocking_send(Err(std::io::Error::other(e))).unwrap_or(());
+        }
+    });
+
+    Body::from_stream(futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }))
+}
+
+fn write_zip(git_dir: &std::path::Path, tree_id: Oid, time: Option<DateTime<FixedOffset>>, chunks: ZipChunks) -> Result<(), zip::result::ZipError> {
+    use chrono::{Datelike, Timelike};
+    use zip::write::SimpleFileOptions;
+
+    let repo = Repository::open(git_dir).map_err(std::io::Error::other)?;
+    let tree = repo.find_tree(tree_id).map_err(std::io::Error::other)?;
+
+    // Collect the files first since the tree walk cannot be aborted with an error
+    let mut files = Vec::new();
+    tree.walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+        // Skip trees and submodules
+        if entry.kind() == Some(git2::ObjectType::Blob) {
+            if let Some(name) = entry.name() {
+                files.push((format!("{}{}", dir, name), entry.id(), entry.filemode()));
+            }
+        }
+        git2::TreeWalkResult::Ok
+    }).map_err(std::io::Error::other)?;
+
+    let mut options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    if let Some(time) = time {
+        let time = zip::DateTime::from_date_and_time(time.year() as u16, time.month() as u8, time.day() as u8, time.hour() as u8, time.minute() as u8, time.second() as u8);
+        // Times before 1980 cannot be represented
+        if let Ok(time) = time {
+            options = options.last_modified_time(time);
+        }
+    }
+
+    let mut zip = zip::ZipWriter::new(chunks);
+    // Send each file once its header is complete
+    zip.set_flush_on_finish_file(true);
+    for (path, blob_id, mode) in files {
+        let blob = repo.find_blob(blob_id).map_err(std::io::Error::other)?;
+        if mode == 0o120000 {
+            zip.add_symlink(path, String::from_utf8_lossy(blob.content()), options)?;
+            continue;
+        }
+        let permissions = if mode == 0o100755 { 0o755 } else { 0o644 };
+        zip.start_file(path, options.unix_permissions(permissions).large_file(blob.size() as u64 >= u32::MAX as u64))?;
+        zip.write_all(blob.content())?;
+    }
+    zip.finish()?.flush()?;
+    Ok(())
+}
+
+/// Sends bytes written to it as chunks of a response body.
+///
+/// `ZipWriter` seeks back to complete the header of each file after writing its content, so
+/// the bytes are held until it flushes, after which they can no longer be changed.
+struct ZipChunks {
+    tx: tokio::sync::mpsc::Sender<Result<Bytes, std::io::Error>>,
+    /// Bytes written since the last flush, starting at the offset `sent`.
+    buf: Vec<u8>,
+    sent: u64,
+    pos: u64,
+}
+
+impl Write for ZipChunks {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let start = (self.pos - self.sent) as usize;
+        let end = start + data.len();
+        if end > self.buf.len() {
+            self.buf.resize(end, 0);
+        }
+        self.buf[start..end].copy_from_slice(data);
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.sent += self.buf.len() as u64;
+        let chunk = Bytes::from(std::mem::take(&mut self.buf));
+        self.tx.blocking_send(Ok(chunk))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the client has gone"))
+    }
+}
+
+impl std::io::Seek for ZipChunks {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        use std::io::SeekFrom;
+
+        let end = self.sent + self.buf.len() as u64;
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => end.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        match pos {
+            Some(pos) if pos >= self.sent => {
+                self.pos = pos;
+                Ok(pos)
+            },
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "cannot seek back into bytes already sent")),
+        }
+    }
+}
+
+impl std::io::Read for ZipChunks {
+    // ZipWriter requires this, but never reads back what it has written when only adding files
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported))
+    }
+}
+
+async fn post_import(
+    State(state): State<Arc<AppState>>,
+    claims: Option<Extension<Claims>>,
+    mut multipart: Multipart,
+) -> Result<Response, GitError> {
+    debug!("post_import");
+
+    // Take the first file in the form data as the archive
+    let mut archive = None;
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Ok(ApiError::from(e).into_response()),
+        };
+        if field.file_name().is_none() {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match field.chunk().await {
+                Ok(Some(chunk)) => {
+                    if data.len() + chunk.len() > state.config.max_upload_bytes {
+                        return Ok(ApiError::payload_too_large(format!("the archive must not exceed {} bytes", state.config.max_upload_bytes)).into_response());
+                    }
+                    data.extend_from_slice(&chunk);
+                },
+                Ok(None) => break,
+                Err(e) => return Ok(ApiError::from(e).into_response()),
+            }
+        }
+        archive = Some(data);
+        break;
+    }
+    let Some(archive) = archive else {
+        return Ok(ApiError::bad_request("no archive given").into_response());
+    };
+
+    // Unpack without holding the repository
+    let max_bytes = state.config.max_upload_bytes;
+    let max_total_bytes = state.config.max_import_bytes;
+    let unpacked = tokio::task::spawn_blocking(move || unpack_zip(&archive, max_bytes, max_total_bytes, MAX_IMPORT_ENTRIES)).await.map_err(|e| GitError(e.to_string()))?;
+    let (files, skipped) = match unpacked {
+        Ok(unpacked) => unpacked,
+        Err(e) => return Ok(e.into_response()),
+    };
+    if files.is_empty() {
+        return Ok(ApiError::bad_request("no files to import in the archive").into_response());
+    }
+
+    commit_then_reply(&state, |repo| {
+        let (head_commit, mut index) = head_index(repo)?;
+        for file in &files {
+            let blob_oid = repo.blob(&file.content).map_err(|e| GitError(format!("failed to write blob: {}", e.message())))?;
+            if let Err(e) = check_file_dir_conflict(&index, &file.path) {
+                return Ok(Err(e.into_response()));
+            }
+            // Existing files are overwritten
+            index.add(&blob_entry(&file.path, blob_oid, file.mode))?;
+        }
+
+        let commit_id = commit_index(repo, index, head_commit, &format!("Import {} files from archive", files.len()), claims.as_deref())?;
+        let paths: Vec<String> = files.into_iter().map(|file| file.path).collect();
+        Ok(Ok((commit_id, paths.clone(), Imported { commit: commit_id.to_string(), paths, skipped })))
+    }).await
+}
+
+/// A file read from a zip archive.
+struct ArchivedFile {
+    path: String,
+    content: Vec<u8>,
+    mode: u32,
+}
+
+/// Number of entries an imported archive may have.
+const MAX_IMPORT_ENTRIES: usize = 10_000;
+
+/// Reads the files in a zip archive of up to `max_entries` entries, each file of up to `max_bytes`
+/// and all of them of up to `max_total_bytes`, so that a small archive cannot unpack to exhaust memory.
+/// Entries whose paths would escape the repository are skipped, and their names are returned as well.
+fn unpack_zip(data: &[u8], max_bytes: usize, max_total_bytes: usize, max_entries: usize) -> Result<(Vec<ArchivedFile>, Vec<String>), ApiError> {
+    use std::io::Read;
+
+    let invalid = |e: zip::result::ZipError| ApiError::bad_request(format!("invalid archive: {}", e));
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data)).map_err(invalid)?;
+    if archive.len() > max_entries {
+        return Err(ApiError::payload_too_large(format!("the archive must not have more than {} entries", max_entries)));
+    }
+    let mut total_bytes = 0;
+    let mut files = Vec::new();
+    let mut skipped = Vec::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i).map_err(invalid)?;
+        if file.is_dir() {
+            continue;
+        }
+        // Refuse absolute paths and those going up out of the root
+        let path = file.enclosed_name()
+            .and_then(|path| path.to_str().map(str::to_owned))
+            .filter(|path| validate_path(path).is_ok());
+        let Some(path) = path else {
+            skipped.push(file.name().to_owned());
+            continue;
+        };
+
+        // The sizes recorded in the archive cannot be trusted
+        let limit = max_bytes.min(max_total_bytes - total_bytes);
+        let mut content = Vec::new();
+        (&mut file).take(limit as u64 + 1).read_to_end(&mut content).map_err(|e| ApiError::bad_request(format!("invalid archive: {}", e)))?;
+        if content.len() > max_bytes {
+            return Err(ApiError::payload_too_large(format!("{} in the archive exceeds {} bytes", path, max_bytes)));
+        }
+        total_bytes += content.len();
+        if total_bytes > max_total_bytes {
+            return Err(ApiError::payload_too_large(format!("the files in the archive exceed {} bytes in total", max_total_bytes)));
+        }
+        let mode = if file.is_symlink() {
+            0o120000
+        }
+        else if file.unix_mode().is_some_and(|mode| mode & 0o111 != 0) {
+            0o100755
+        }
+        else {
+            0o100644
+        };
+        files.push(ArchivedFile { path, content, mode });
+    }
+    Ok((files, skipped))
+}
+
+async fn delete_files_path(
+    RepoPath(path): RepoPath,
+    State(state): State<Arc<AppState>>,
+    claims: Option<Extension<Claims>>,
+) -> Result<Response, GitError> {
+    debug!("delete_files_path");
+
+    commit_then_reply(&state, |repo| {
+        let Some(commit_id) = delete_entry(repo, &path, &format!("Delete file {}", &path), claims.as_deref())? else {
+            return Ok(Err(ApiError::not_found(format!("{} not found", path)).into_response()));
+        };
+        Ok(Ok((commit_id, vec![path.clone()], Committed { commit: commit_id.to_string(), path, moved: None })))
+    }).await
+}
+
+async fn post_files(
+    State(state): State<Arc<AppState>>,
+    claims: Option<Extension<Claims>>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Response, GitError> {
+    debug!("post_files_path");
+
+    // The target directory can be given either as a header or as a form field
+    let mut dir = headers
+        .get("x-upload-dir")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_owned());
+    let mut overwrite = false;
+
+    // Create a blob for each part (file) in the form data
+    let mut files = Vec::new();
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Ok(ApiError::from(e).into_response()),
+        };
+        debug!("{:?}", field);
+
+        let uuid = field.name().unwrap_or_default().to_owned();
+        let filename = match field.file_name() {
+            Some(filename) => filename.to_owned(),
+            None => {
+                // Not a file but an option
+                let value = match field.text().await {
+                    Ok(value) => value,
+                    Err(e) => return Ok(ApiError::from(e).into_response()),
+                };
+                match uuid.as_str() {
+                    "path" => dir = Some(value),
+                    "overwrite" => overwrite = value == "true",
+                    _ => (),
+                }
+                continue;
+            },
+        };
+
+        let blob_oid = {
+            // Read the part while enforcing the size limit
+            let mut data = Vec::new();
+            loop {
+                match field.chunk().await {
+                    Ok(Some(chunk)) => {
+                        if data.len() + chunk.len() > state.config.max_upload_bytes {
+                            // Abort without committing anything
+                            return Ok(ApiError::payload_too_large(format!("each file must not exceed {} bytes", state.config.max_upload_bytes)).into_response());
+                        }
+                        data.extend_from_slice(&chunk);
+                    },
+                    Ok(None) => break,
+                    Err(e) => return Ok(ApiError::from(e).into_response()),
+                }
+            }
+
+            let repo = state.repo.write().await?;
+            let mut writer = repo.blob_writer(None)?;
+            writer.write_all(&data).map_err(|e| GitError(format!("failed to write blob: {}", e)))?;
+            writer.commit()?
+        };
+
+        files.push((uuid, filename, blob_oid));
+    }
+
+    // Commit
+    commit_then_reply(&state, |repo| {
+        let (head_commit, mut index) = head_index(repo)?;
+
+        let count = files.len();
+        let mut uploaded = Vec::with_capacity(count);
+        for (uuid, filename, blob_oid) in files {
+            let path = match &dir {
+                Some(dir) if !dir.trim_matches('/').is_empty() => format!("{}/{}", dir.trim_matches('/'), filename),
+                _ => filename,
+            };
+            if let Err(e) = validate_path(&path) {
+                return Ok(Err(e.into_response()));
+            }
+            if !overwrite && index.get_path(std::path::Path::new(&path), 0).is_some() {
+                return Ok(Err(ApiError::new(StatusCode::CONFLICT, "conflict", format!("{} already exists", path)).into_response()));
+            }
+
+            let entry = blob_entry(&path, blob_oid, 0o100644);
+            if let Err(e) = check_file_dir_conflict(&index, &path) {
+                return Ok(Err(e.into_response()));
+            }
+            index.add(&entry)?;
+            uploaded.push(UploadedFile { field: uuid, path, blob: blob_oid.to_string() });
+        }
+
+        let commit_id = commit_index(repo, index, head_commit, &format!("Upload {} files", count), claims.as_deref())?;
+        Ok(Ok((commit_id, uploaded.iter().map(|file| file.path.clone()).collect(), Uploaded { commit: commit_id.to_string(), files: uploaded })))
+    }).await
+}
+
+/// A JSON request body, which is rejected with an `ApiError` like any other error.
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(ApiError))]
+struct ApiJson<T>(T);
+
+/// A query string, which is rejected with an `ApiError` like any other error.
+#[derive(FromRequestParts)]
+#[from_request(via(Query), rejection(ApiError))]
+struct ApiQuery<T>(T);
+
+/// A path in the repository given as `*path` in a route, which is percent-decoded and validated.
+struct RepoPath(String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RepoPath {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(path) = Path::<String>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| ApiError::bad_request(format!("invalid path: {:?}", parts.uri.path())))?;
+        validate_path(&path)?;
+        Ok(RepoPath(path))
+    }
+}
+
+/// Checks that a path given by a client stays inside the repository.
+fn validate_path(path: &str) -> Result<(), ApiError> {
+    let valid = !path.is_empty()
+        && !path.contains('\0')
+        && path.split('/').all(|segment| !segment.is_empty() && segment != "." && segment != "..");
+    if valid {
+        Ok(())
+    }
+    else {
+        Err(ApiError::bad_request(format!("invalid path: {:?}", path)))
+    }
+}
+
+/// Checks that a file can be put at the path without replacing a file with a directory or vice versa,
+/// which git would do silently.
+fn check_file_dir_conflict(index: &Index, path: &str) -> Result<(), ApiError> {
+    for (i, _) in path.match_indices('/') {
+        let parent = &path[..i];
+        if index.get_path(std::path::Path::new(parent), 0).is_some() {
+            return Err(ApiError::new(StatusCode::CONFLICT, "conflict", format!("{} is a file", parent)));
+        }
+    }
+    if index.find_prefix(format!("{}/", path)).is_ok() {
+        return Err(ApiError::new(StatusCode::CONFLICT, "conflict", format!("{} is a directory", path)));
+    }
+    Ok(())
+}
+
+fn guess_mime_type<P: AsRef<std::path::Path>>(path: P) -> String {
+    mime_guess::from_path(path)
+        .first()
+        .map_or_else(|| "application/octet-stream".to_string(), |mime| mime.to_string())
+}
+
+fn commit_time(commit: &git2::Commit) -> DateTime<FixedOffset> {
+    let t = commit.time();
+    let tz = FixedOffset::east_opt(t.offset_minutes() * 60).unwrap();
+    tz.timestamp_opt(t.seconds(), 0).unwrap()
+}
+
+/// Returns the author time of the latest commit touching the path in HEAD.
+/// Results are cached, and kept across new commits for paths they do not touch.
+/// History is walked without holding the cache, so that reads of other notes need not wait.
+fn last_modified(state: &AppState, repo: &Repo, path: &str) -> Result<Option<DateTime<FixedOffset>>, GitError> {
+    let head_commit_id = repo.head_commit()?.id();
+    let cached_commit_id = match &*state.cached_last_modified.lock().unwrap() {
+        Cached::Computed { commit_id, data } if *commit_id == head_commit_id => match data.get(path) {
+            Some(time) => return Ok(Some(*time)),
+            None => None,
+        },
+        Cached::Computed { commit_id, .. } => Some(*commit_id),
+        Cached::None => None,
+    };
+    // Find out which of the cached times the new commits since then have outdated
+    let outdated = match cached_commit_id {
+        Some(old) if repo.graph_descendant_of(head_commit_id, old)? => Some((old, touched_paths(repo, old, head_commit_id)?)),
+        _ => None,
+    };
+
+    let mut time = None;
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+    revwalk.push(head_commit_id)?;
+    for oid in revwalk {
+        let commit = repo.find_commit(oid?)?;
+        if !paths_changed_by(repo, &commit, Some(path))?.is_empty() {
+            let when = commit.author().when();
+            let tz = FixedOffset::east_opt(when.offset_minutes() * 60).unwrap();
+            time = Some(tz.timestamp_opt(when.seconds(), 0).unwrap());
+            break;
+        }
+    }
+
+    let mut cached_last_modified = state.cached_last_modified.lock().unwrap();
+    if let (Some((old, paths)), Cached::Computed { commit_id, data }) = (outdated, &mut *cached_last_modified) {
+        // Unless someone else has moved the cache on meanwhile
+        if *commit_id == old {
+            data.retain(|path, _| !paths.contains(path));
+            *commit_id = head_commit_id;
+        }
+    }
+    if let Some(time) = time {
+        cached_last_modified.get_or_reset(head_commit_id, HashMap::new).insert(path.to_owned(), time);
+    }
+    Ok(time)
+}
+
+/// Lists the paths the commit changed from its first parent, looking only at the given one if any.
+fn paths_changed_by(repo: &Repo, commit: &git2::Commit, path: Option<&str>) -> Result<Vec<String>, GitError> {
+    let mut opts = DiffOptions::new();
+    if let Some(path) = path {
+        opts.pathspec(path).disable_pathspec_match(true);
+    }
+    let parent_tree = match commit.parents().next() {
+        Some(parent) => Some(parent.tree()?),
+        None => None,
+    };
+    let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), Some(&mut opts))?;
+    Ok(diff.deltas()
+        .filter_map(|delta| delta.new_file().path()?.to_str().map(str::to_owned))
+        .collect())
+}
+
+/// Collects the paths changed by the commits after `old` up to `new`.
+fn touched_paths(repo: &Repo, old: Oid, new: Oid) -> Result<std::collections::HashSet<String>, GitError> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(new)?;
+    revwalk.hide(old)?;
+    let mut paths = std::collections::HashSet::new();
+    for oid in revwalk {
+        paths.extend(paths_changed_by(repo, &repo.find_commit(oid?)?, None)?);
+    }
+    Ok(paths)
+}
+
+/// Walks the commits of the served branch, newest first.
+fn branch_revwalk(repo: &Repo) -> Result<git2::Revwalk<'_>, git2::Error> {
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::TIME)?;
+    // Nothing to walk before the first commit
+    if !repo.is_unborn() {
+        revwalk.push_ref(repo.head_ref())?;
+    }
+    Ok(revwalk)
+}
+
+/// Formats a time as an HTTP-date.
+fn http_date(time: &DateTime<FixedOffset>) -> HeaderValue {
+    time.with_timezone(&Utc).format("%a, %d %b %Y %H:%M:%S GMT").to_string().parse().unwrap()
+}
+
+/// Tells whether the entity has been modified since `If-Modified-Since` if present.
+fn modified_since(headers: &HeaderMap, modified: &DateTime<FixedOffset>) -> Option<bool> {
+    let since = headers.get(header::IF_MODIFIED_SINCE)?.to_str().ok()?;
+    let since = DateTime::parse_from_rfc2822(since).ok()?;
+    Some(modified.timestamp() > since.timestamp())
+}
+
+fn entity_tag(oid: Oid) -> HeaderValue {
+    format!("\"{}\"", oid).parse().unwrap()
+}
+
+/// Makes an ETag for representations which differ but mean the same, e.g. compressed or not.
+fn weak_entity_tag(oid: Oid) -> HeaderValue {
+    format!("W/\"{}\"", oid).parse().unwrap()
+}
+
+/// Tells whether any of the tags in `If-None-Match` matches, weak or not.
+fn etag_matches(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let etag = etag.to_str().unwrap().trim_start_matches("W/");
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_range| media_range.split(';').next().unwrap().trim() == "application/json")
+}
+
+fn get_first_toplevel_rank1_heading(node: &markdown::mdast::Node) -> Option<&markdown::mdast::Node> {
+    use markdown::mdast::Node;
+    if let Node::Root(root) = node {
+        for child in root.children.iter() {
+            if let Node::Heading(heading) = child {
+                if heading.depth == 1 {
+                    return Some(child);
+                }
+            }
+        }
+        None
+    }
+    else {
+        None
+    }
+}
+
+fn parse_markdown(text: &str) -> Option<markdown::mdast::Node> {
+    let mut opts = markdown::ParseOptions::gfm();
+    opts.constructs.frontmatter = true;
+    markdown::to_mdast(text, &opts).ok()
+}
+
+/// Extracts metadata from the content only if it is of a note.
+fn note_metadata(state: &AppState, path: &std::path::Path, blob: &[u8]) -> (Option<Metadata>, Option<String>) {
+    if state.config.is_note(path) {
+        extract_metadata(blob)
+    }
+    else {
+        (None, None)
+    }
+}
+
+fn extract_metadata(blob: &[u8]) -> (Option<Metadata>, Option<String>) {
+    if let Ok(text) = std::str::from_utf8(blob) {
+        let (metadata, body) = split_frontmatter(text);
+        let title = parse_markdown(body)
+            .and_then(|node| get_first_toplevel_rank1_heading(&node).map(|heading_node| heading_node.to_string()));
+        (metadata, title)
+    }
+    else {
+        (None, None)
+    }
+}
+
+/// Splits a note into its parsed frontmatter and the body following it.
+fn split_frontmatter(text: &str) -> (Option<Metadata>, &str) {
+    match parse_front_matter(text) {
+        Some((metadata, body)) => (Some(metadata), body.trim_start_matches(['\r', '\n'])),
+        None => (None, text),
+    }
+}
+
+mod models {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{self, Write};
+    use std::mem::ManuallyDrop;
+    use std::net::IpAddr;
+    use std::ops::Deref;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::{self, Instant};
+    use std::option::Option;
+
+    use axum::{
+        extract::{
+            multipart::MultipartError,
+            rejection::{JsonRejection, QueryRejection},
+        },
+        http::{HeaderValue, StatusCode},
+        Json,
+        response::{IntoResponse, Response},
+    };
+    use chrono::{DateTime, Duration, FixedOffset};
+    use git2::{Repository, Oid};
+    use jsonwebtoken as jwt;
+    use metrics_exporter_prometheus::PrometheusHandle;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use tracing::error;
+    use tokio::sync::{broadcast, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+    use tokio_util::sync::CancellationToken;
+
+    pub type Metadata = serde_yaml::Value;
+
+    /// Parses the front matter at the start of a note, which is YAML between `---` lines,
+    /// TOML between `+++` lines or a JSON object closed by a `}` line.
+    /// Returns the metadata together with the rest of the note, or `None` without front matter.
+    /// Malformed front matter gives the `error` in parsing it as the metadata instead.
+    pub fn parse_front_matter(text: &str) -> Option<(Metadata, &str)> {
+        let first_line = text.split_inclusive('\n').next()?;
+        let (closing, start) = match first_line.trim_end() {
+            "---" => ("---", first_line.len()),
+            "+++" => ("+++", first_line.len()),
+            // The braces belong to the JSON object
+            _ if text.starts_with('{') => ("}", 0),
+            _ => return None,
+        };
+
+        let mut end = start;
+        for line in text[start..].split_inclusive('\n') {
+            if line.trim_end() == closing {
+                let parsed = match closing {
+                    "---" => serde_yaml::from_str(&text[start..end]).map_err(|e| e.to_string()),
+                    "+++" => toml::from_str(&text[start..end]).map(toml_to_metadata).map_err(|e| e.to_string()),
+                    _ => serde_json::from_str(&text[..end + line.len()]).map_err(|e| e.to_string()),
+                };
+                let metadata = parsed.unwrap_or_else(|e| {
+                    let mut error_object = serde_yaml::Mapping::new();
+                    error_object.insert("error".into(), e.into());
+                    Metadata::Mapping(error_object)
+                });
+                return Some((metadata, &text[end + line.len()..]));
+            }
+            end += line.len();
+        }
+        // Not closed
+        None
+    }
+
+    fn toml_to_metadata(value: toml::Value) -> Metadata {
+        match value {
+            toml::Value::String(s) => Metadata::String(s),
+            toml::Value::Integer(i) => Metadata::Number(i.into()),
+            toml::Value::Float(f) => Metadata::Number(f.into()),
+            toml::Value::Boolean(b) => Metadata::Bool(b),
+            // Kept as written, as dates are in YAML
+            toml::Value::Datetime(datetime) => Metadata::String(datetime.to_string()),
+            toml::Value::Array(values) => Metadata::Sequence(values.into_iter().map(toml_to_metadata).collect()),
+            toml::Value::Table(table) => Metadata::Mapping(
+                table.into_iter().map(|(key, value)| (Metadata::String(key), toml_to_metadata(value))).collect()
+            ),
+        }
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct ListEntry {
+        pub path: PathBuf,
+        pub size: usize,
+        pub mime_type: String,
+        pub metadata: Option<Metadata>,
+        pub title: Option<String>,
+        pub time: DateTime<FixedOffset>,
+        /// Only filled in on request and never cached.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub last_commit: Option<LastCommit>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct LastCommit {
+        pub id: String,
+        pub author: String,
+        pub time: DateTime<FixedOffset>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ListQuery {
+        pub offset: Option<usize>,
+        pub limit: Option<usize>,
+        pub sort: Option<String>,
+        #[serde(default)]
+        pub with_history: bool,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct TreeEntry {
+        pub name: String,
+        /// Either `blob` or `tree`.
+        pub kind: &'static str,
+        /// Id of the blob or the tree, which changes whenever the content does.
+        pub oid: String,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct FileEntry {
+        pub path: PathBuf,
+        pub size: usize,
+        pub mime_type: String,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct ListPage {
+        pub total: usize,
+        pub entries: Vec<ListEntry>,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct NoteContent {
+        pub content: String,
+        pub metadata: Option<Metadata>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct SearchQuery {
+        pub q: String,
+        #[serde(default)]
+        pub case_sensitive: bool,
+        pub limit: Option<usize>,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct SearchHit {
+        pub path: PathBuf,
+        pub line_number: usize,
+        pub snippet: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct NoteQuery {
+        pub diff: Option<String>,
+        /// Commits to diff `<path>/diff` between, the newer one defaulting to HEAD.
+        pub from: Option<String>,
+        pub to: Option<String>,
+        pub rev: Option<String>,
+        /// `html` to render markdown notes on the server
+        pub render: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ExportQuery {
+        /// Commit to export instead of HEAD.
+        pub rev: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct DeleteQuery {
+        /// Whether to delete for good rather than moving to the trash.
+        #[serde(default)]
+        pub purge: bool,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct HistoryQuery {
+        pub limit: Option<usize>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct LogQuery {
+        pub offset: Option<usize>,
+        pub limit: Option<usize>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct LogEntry {
+        pub id: String,
+        pub author: String,
+        pub email: String,
+        pub timestamp: DateTime<FixedOffset>,
+        /// First line of the commit message.
+        pub summary: String,
+    }
+
+    #[derive(Debug, Serialize, Clone)]
+    pub struct HistoryEntry {
+        pub commit_id: String,
+        pub author: String,
+        pub email: String,
+        pub time: DateTime<FixedOffset>,
+        pub message: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct GitCommit {
+        pub id: String,
+        pub tree: String,
+        pub parents: Vec<String>,
+        pub author: GitSignature,
+        pub message: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct GitSignature {
+        pub name: String,
+        pub email: String,
+        pub time: DateTime<FixedOffset>,
+    }
+
+    /// Who last changed a line of a note, one for each line.
+    #[derive(Debug, Serialize, Clone)]
+    pub struct BlameLine {
+        pub commit_id: String,
+        pub author: String,
+        pub email: String,
+        pub time: DateTime<FixedOffset>,
+    }
+
+    /// A machine-readable error replied as a JSON body.
+    #[derive(Debug, Serialize, Clone)]
+    pub struct ApiError {
+        pub code: u16,
+        pub message: String,
+        pub kind: String,
+    }
+
+    impl ApiError {
+        pub fn new(status: StatusCode, kind: &str, message: impl Into<String>) -> ApiError {
+            ApiError {
+                code: status.as_u16(),
+                message: message.into(),
+                kind: kind.to_owned(),
+            }
+        }
+
+        pub fn bad_request(message: impl Into<String>) -> ApiError {
+            ApiError::new(StatusCode::BAD_REQUEST, "bad_request", message)
+        }
+
+        pub fn unauthorized(message: impl Into<String>) -> ApiError {
+            ApiError::new(StatusCode::UNAUTHORIZED, "unauthorized", message)
+        }
+
+        pub fn not_found(message: impl Into<String>) -> ApiError {
+            ApiError::new(StatusCode::NOT_FOUND, "not_found", message)
+        }
+
+        pub fn payload_too_large(message: impl Into<String>) -> ApiError {
+            ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large", message)
+        }
+
+        /// Tells why an extractor rejected a request, keeping the status it chose.
+        fn rejected(status: StatusCode, message: String) -> ApiError {
+            let kind = match status {
+                StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+                StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+                StatusCode::UNPROCESSABLE_ENTITY => "unprocessable_entity",
+                _ => "bad_request",
+            };
+            ApiError::new(status, kind, message)
+        }
+    }
+
+    impl From<MultipartError> for ApiError {
+        fn from(e: MultipartError) -> Self {
+            let kind = if e.status() == StatusCode::PAYLOAD_TOO_LARGE { "payload_too_large" } else { "bad_request" };
+            ApiError::new(e.status(), kind, e.body_text())
+        }
+    }
+
+    impl From<JsonRejection> for ApiError {
+        fn from(e: JsonRejection) -> Self {
+            ApiError::rejected(e.status(), e.body_text())
+        }
+    }
+
+    impl From<QueryRejection> for ApiError {
+        fn from(e: QueryRejection) -> Self {
+            ApiError::rejected(e.status(), e.body_text())
+        }
+    }
+
+    impl IntoResponse for ApiError {
+        fn into_response(self) -> Response {
+            let status = StatusCode::from_u16(self.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            (status, Json(self)).into_response()
+        }
+    }
+
+    /// An error from a git operation, reported as an internal server error.
+    #[derive(Debug)]
+    pub struct GitError(pub String);
+
+    impl From<git2::Error> for GitError {
+        fn from(e: git2::Error) -> Self {
+            GitError(e.message().to_owned())
+        }
+    }
+
+    impl IntoResponse for GitError {
+        fn into_response(self) -> Response {
+            error!("git error: {}", self.0);
+            ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "git", self.0).into_response()
+        }
+    }
+
+    /// A write based on a commit other than the current HEAD.
+    #[derive(Debug)]
+    pub struct Conflict {
+        pub head: String,
+    }
+
+    impl IntoResponse for Conflict {
+        fn into_response(self) -> Response {
+            let error = ApiError::new(StatusCode::CONFLICT, "conflict", "HEAD has moved since the base commit");
+            (StatusCode::CONFLICT, Json(json!({
+                "code": error.code,
+                "message": error.message,
+                "kind": error.kind,
+                "head": self.head,
+            }))).into_response()
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize, Clone)]
+    pub struct Claims {
+        pub sub: String,
+        pub exp: usize,
+        pub email: String,
+        /// ID of the token, by which it is revoked on logout.
+        pub jti: String,
+    }
+
+    pub enum Cache<'a, T> {
+        Valid(&'a T),
+        Invalid(Oid, &'a T),
+        None,
+    }
+
+    pub enum Cached<T> {
+        Computed {
+            commit_id: Oid,
+            data: T,
+        },
+        None,
+    }
+
+    impl<T> Cached<T> {
+        /// Returns the data computed for the commit, starting over with `init()` if it was for another one.
+        pub fn get_or_reset(&mut self, commit_id: Oid, init: impl FnOnce() -> T) -> &mut T {
+            if !matches!(self, Cached::Computed { commit_id: id, .. } if *id == commit_id) {
+                *self = Cached::Computed {
+                    commit_id,
+                    data: init(),
+                };
+            }
+            match self {
+                Cached::Computed { data, .. } => data,
+                Cached::None => unreachable!(),
+            }
+        }
+
+        pub fn get(&self, repo: &Repo) -> Cache<'_, T> {
+            match self {
+                Cached::None => Cache::None,
+                Cached::Computed { commit_id, data } => {
+                    match repo.head_commit() {
+                        Ok(commit) if *commit_id == commit.id() => Cache::Valid(data),
+                        Ok(_) => Cache::Invalid(*commit_id, data),
+                        // HEAD no longer points to a commit, so start over
+                        Err(_) => Cache::None,
+                    }
+                },
+            }
+        }
+    }
+
+    pub type ModifiedTimes = HashMap<String, DateTime<FixedOffset>>;
+
+    #[derive(Deserialize, Serialize)]
+    pub struct EntriesCache {
+        pub commit_id: String,
+        pub entries: Vec<ListEntry>,
+    }
+
+    /// The repository shared among handlers.
+    ///
+    /// `git2::Repository` is `Send` but not `Sync`, so a single handle cannot be read from
+    /// several tasks at once even behind a `RwLock`. Instead each lock holder gets its own
+    /// handle, taken from the idle ones so that their object caches are kept between requests.
+    ///
+    /// Requests which only read the repository run under the read lock, so readers run concurrently.
+    /// Those which commit run under the write lock, as they create objects and move HEAD,
+    /// so writers exclude each other and the readers.
+    pub struct SharedRepo {
+        git_dir: PathBuf,
+        head_ref: String,
+        lock: RwLock<()>,
+        /// Handles not in use, as many as have ever been used at once.
+        idle: Arc<std::sync::Mutex<Vec<Repository>>>,
+    }
+
+    /// A repository handle which knows the reference notes are served from.
+    /// The handle is put back among the idle ones when dropped.
+    pub struct Repo {
+        repo: ManuallyDrop<Repository>,
+        head_ref: String,
+        idle: Arc<std::sync::Mutex<Vec<Repository>>>,
+    }
+
+    impl Deref for Repo {
+        type Target = Repository;
+
+        fn deref(&self) -> &Repository {
+            &self.repo
+        }
+    }
+
+    impl Drop for Repo {
+        fn drop(&mut self) {
+            // SAFETY: the handle is not used after being taken out here
+            let repo = unsafe { ManuallyDrop::take(&mut self.repo) };
+            if let Ok(mut idle) = self.idle.lock() {
+                idle.push(repo);
+            }
+        }
+    }
+
+    impl Repo {
+        /// Name of the reference to read notes from and commit to, `HEAD` unless a branch is configured.
+        pub fn head_ref(&self) -> &str {
+            &self.head_ref
+        }
+
+        /// Resolves the served reference to the branch it is or points to.
+        pub fn branch_ref(&self) -> Result<git2::Reference<'_>, git2::Error> {
+            self.repo.find_reference(&self.head_ref)?.resolve()
+        }
+
+        /// Resolves the commit the served reference points to.
+        pub fn head_commit(&self) -> Result<git2::Commit<'_>, git2::Error> {
+            self.repo.find_reference(&self.head_ref)?.peel_to_commit()
+        }
+
+        /// Tells whether the served branch has no commits yet, as in a freshly initialized repository.
+        pub fn is_unborn(&self) -> bool {
+            match self.head_commit() {
+                Ok(_) => false,
+                Err(e) => matches!(e.code(), git2::ErrorCode::NotFound | git2::ErrorCode::UnbornBranch),
+            }
+        }
+
+        /// Resolves the tree of the head commit, which is empty before the first commit.
+        pub fn head_tree(&self) -> Result<git2::Tree<'_>, git2::Error> {
+            if self.is_unborn() {
+                let tree_id = self.repo.treebuilder(None)?.write()?;
+                return self.repo.find_tree(tree_id);
+            }
+            self.head_commit()?.tree()
+        }
+    }
+
+    pub struct RepoGuard<G> {
+        repo: Repo,
+        _guard: G,
+    }
+
+    impl<G> Deref for RepoGuard<G> {
+        type Target = Repo;
+
+        fn deref(&self) -> &Repo {
+            &self.repo
+        }
+    }
+
+    impl SharedRepo {
+        pub fn new(repo: &Repository, branch: Option<&str>) -> SharedRepo {
+            SharedRepo {
+                git_dir: repo.path().to_owned(),
+                head_ref: branch.map_or_else(|| "HEAD".to_owned(), |branch| format!("refs/heads/{}", branch)),
+                lock: RwLock::new(()),
+                idle: Arc::new(std::sync::Mutex::new(Vec::new())),
+            }
+        }
+
+        /// Takes an idle handle to the repository, or opens a new one, without locking.
+        /// This is only for reading refs and objects, which git updates atomically, e.g. to push them.
+        pub fn open_unlocked(&self) -> Result<Repo, GitError> {
+            let idle = self.idle.lock().ok().and_then(|mut idle| idle.pop());
+            let repo = match idle {
+                Some(repo) => repo,
+                None => Repository::open(&self.git_dir)?,
+            };
+            Ok(Repo {
+                repo: ManuallyDrop::new(repo),
+                head_ref: self.head_ref.clone(),
+                idle: Arc::clone(&self.idle),
+            })
+        }
+
+        /// Opens the repository for reading, e.g. looking up trees and blobs.
+        pub async fn read(&self) -> Result<RepoGuard<RwLockReadGuard<'_, ()>>, GitError> {
+            let guard = self.lock.read().await;
+            Ok(RepoGuard {
+                repo: self.open_unlocked()?,
+                _guard: guard,
+            })
+        }
+
+        /// Waits until no one is writing to the repository.
+        pub async fn wait_for_writers(&self) {
+            let _guard = self.lock.write().await;
+        }
+
+        /// Opens the repository for writing blobs and commits.
+        pub async fn write(&self) -> Result<RepoGuard<RwLockWriteGuard<'_, ()>>, GitError> {
+            let guard = self.lock.write().await;
+            Ok(RepoGuard {
+                repo: self.open_unlocked()?,
+                _guard: guard,
+            })
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct AppState {
+        pub repo: Arc<SharedRepo>,
+        pub cached_entries: Arc<Mutex<Cached<Vec<ListEntry>>>>,
+        /// Blobs in HEAD by their paths.
+        /// This is locked only briefly without awaiting, hence a blocking mutex.
+        pub cached_blob_ids: Arc<std::sync::Mutex<Cached<HashMap<String, Oid>>>>,
+        /// Times of the latest commits touching paths, filled in as they are requested.
+        pub cached_last_modified: Arc<std::sync::Mutex<Cached<ModifiedTimes>>>,
+        /// IDs of the tokens revoked by logging out, mapped to their expiry.
+        /// This is kept only in memory and is lost on restart, which is fine
+        /// as tokens expire anyway.
+        pub revoked_tokens: Arc<Mutex<HashMap<String, usize>>>,
+        /// Held while pushing to the remote so that pushes do not race.
+        pub push_lock: Arc<Mutex<()>>,
+        /// Numbers of login attempts per client since the first of them.
+        pub login_attempts: Arc<Mutex<HashMap<IpAddr, (u32, Instant)>>>,
+        pub config: Arc<Config>,
+        /// Renders the metrics unless disabled.
+        pub metrics: Option<PrometheusHandle>,
+        /// Tells the subscribers of `/events` about each commit made.
+        pub events: broadcast::Sender<RepoEvent>,
+        /// Cancelled when the server is shutting down.
+        pub shutdown: CancellationToken,
+    }
+
+    impl AppState {
+        pub fn new(repo: Repository, config: Config, metrics: Option<PrometheusHandle>) -> AppState {
+            let cache = match File::open(&config.cache_file) {
+                Ok(file) => {
+                    if let Ok(cache) = rmp_serde::from_read::<_, EntriesCache>(file) {
+                        Cached::Computed {
+                            commit_id: Oid::from_str(&cache.commit_id).unwrap(),
+                            data: cache.entries,
+                        }
+                    }
+                    else {
+                        Cached::None
+                    }
+                },
+                Err(_) => {
+                    Cached::None
+                },
+            };
+            AppState {
+                repo: Arc::new(SharedRepo::new(&repo, config.branch.as_deref())),
+                cached_entries: Arc::new(Mutex::new(cache)),
+                cached_blob_ids: Arc::new(std::sync::Mutex::new(Cached::None)),
+                cached_last_modified: Arc::new(std::sync::Mutex::new(Cached::None)),
+                revoked_tokens: Arc::new(Mutex::new(HashMap::new())),
+                push_lock: Arc::new(Mutex::new(())),
+                login_attempts: Arc::new(Mutex::new(HashMap::new())),
+                config: Arc::new(config),
+                metrics,
+                events: broadcast::channel(EVENT_CAPACITY).0,
+                shutdown: CancellationToken::new(),
+            }
+        }
+    }
+
+    /// Number of events kept for subscribers which have yet to receive them.
+    pub const EVENT_CAPACITY: usize = 64;
+
+    /// Server configuration read from environment variables at startup.
+    pub struct Config {
+        pub git_dir: PathBuf,
+        pub listen: String,
+        pub root_path: String,
+        /// Origins allowed to access the API, or any origin if `None`.
+        pub cors_origins: Option<Vec<HeaderValue>>,
+        /// Keys to sign and verify tokens with, derived from `MORIED_SECRET`.
+        pub encoding_key: jwt::EncodingKey,
+        pub decoding_key: jwt::DecodingKey,
+        pub token_ttl: Duration,
+        pub max_upload_bytes: usize,
+        /// Total size of the files unpacked from an imported archive.
+        pub max_import_bytes: usize,
+        pub users: Mutex<Users>,
+        /// File the list of notes is cached in across restarts.
+        pub cache_file: PathBuf,
+        /// Number of login attempts allowed per client in a minute.
+        pub login_max_attempts: u32,
+        /// Whether to take clients' addresses from `X-Forwarded-For`.
+        pub trust_proxy: bool,
+        /// Paths to the certificate and the private key to serve HTTPS with.
+        pub tls: Option<(PathBuf, PathBuf)>,
+        pub metrics_enabled: bool,
+        /// Whether to compress notes and lists for clients accepting it.
+        pub compression: bool,
+        /// Branch to serve instead of whatever HEAD points to.
+        pub branch: Option<String>,
+        /// Name of the remote to push to after each commit.
+        pub push_remote: Option<String>,
+        /// Credentials for the remote: a private key for SSH or a token for HTTPS.
+        pub ssh_key: Option<PathBuf>,
+        pub push_token: Option<String>,
+        /// Name of the remote to pull from periodically, and how often.
+        pub pull_remote: Option<String>,
+        pub pull_interval: time::Duration,
+        /// Extensions of files listed as notes, in lowercase and without dots.
+        pub note_extensions: Option<Vec<String>>,
+        /// Directory deleted notes are moved into, if enabled.
+        pub trash_dir: Option<String>,
+    }
+
+    impl Config {
+        /// Remote `POST /sync` fetches from, preferring the one pulled from periodically.
+        pub fn sync_remote(&self) -> Option<&str> {
+            self.pull_remote.as_deref().or(self.push_remote.as_deref())
+        }
+
+        /// Tells whether the file is in the trash.
+        pub fn in_trash(&self, path: &Path) -> bool {
+            self.trash_dir.as_ref().is_some_and(|trash_dir| path.starts_with(trash_dir))
+        }
+
+        /// Tells whether the file is a note, which every file is unless MORIED_NOTE_EXTENSIONS is set.
+        pub fn is_note(&self, path: &Path) -> bool {
+            match &self.note_extensions {
+                Some(extensions) => path.extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| extensions.iter().any(|e| e.eq_ignore_ascii_case(ext))),
+                None => true,
+            }
+        }
+
+        /// Reads the configuration, reporting all of the offending variables on failure.
+        pub fn from_env() -> Result<Config, Vec<String>> {
+            let mut errors = Vec::new();
+
+            let git_dir = required_var("MORIED_GIT_DIR").map(PathBuf::from);
+
+            let listen = required_var("MORIED_LISTEN").and_then(|listen| match listen.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(listen),
+                _ => Err(format!("MORIED_LISTEN must be given as <host>:<port>, but got {:?}", listen)),
+            });
+
+            let root_path = required_var("MORIED_ROOT_PATH").and_then(|root_path| {
+                if root_path.starts_with('/') && root_path.ends_with('/') {
+                    Ok(root_path)
+                }
+                else {
+                    Err(format!("MORIED_ROOT_PATH must start and end with '/', but got {:?}", root_path))
+                }
+            });
+
+            let cors_origins = match env::var("MORIED_CORS_ORIGINS") {
+                Ok(origins) => parse_origins("MORIED_CORS_ORIGINS", origins.split(',')).map(Some),
+                // For backward compatibility
+                Err(_) => match env::var("MORIED_ORIGIN_ALLOWED") {
+                    Ok(origin) => parse_origins("MORIED_ORIGIN_ALLOWED", [origin.as_str()]).map(Some),
+                    Err(_) => Ok(None),
+                },
+            };
+
+            let secret = required_var("MORIED_SECRET");
+
+            let token_ttl = match positive_integer_var("MORIED_TOKEN_TTL") {
+                Ok(Some(secs)) => Ok(Duration::seconds(secs)),
+                // For backward compatibility
+                Ok(None) => positive_integer_var("MORIED_SESSION_DURATION").map(|mins| mins.map_or(Duration::hours(6), Duration::minutes)),
+                Err(e) => Err(e),
+            };
+
+            let max_upload_bytes = positive_integer_var("MORIED_MAX_UPLOAD_BYTES")
+                .map(|n| n.map_or(16 * 1024 * 1024, |n| n as usize));
+
+            let max_import_bytes = positive_integer_var("MORIED_MAX_IMPORT_BYTES")
+                .map(|n| n.map_or(256 * 1024 * 1024, |n| n as usize));
+
+            let tls = match (env::var("MORIED_TLS_CERT"), env::var("MORIED_TLS_KEY")) {
+                (Ok(cert), Ok(key)) => Ok(Some((PathBuf::from(cert), PathBuf::from(key)))),
+                (Err(_), Err(_)) => Ok(None),
+                (Ok(_), Err(_)) => Err("MORIED_TLS_KEY must be set together with MORIED_TLS_CERT".to_owned()),
+                (Err(_), Ok(_)) => Err("MORIED_TLS_CERT must be set together with MORIED_TLS_KEY".to_owned()),
+            };
+
+            let metrics_enabled = match env::var("MORIED_METRICS_ENABLED").as_deref() {
+                Ok("true") | Err(_) => Ok(true),
+                Ok("false") => Ok(false),
+                Ok(v) => Err(format!("MORIED_METRICS_ENABLED must be either true or false, but got {:?}", v)),
+            };
+
+            let login_max_attempts = positive_integer_var("MORIED_LOGIN_MAX_ATTEMPTS")
+                .map(|n| n.map_or(5, |n| n.min(u32::MAX as i64) as u32));
+
+            let trust_proxy = match env::var("MORIED_TRUST_PROXY").as_deref() {
+                Ok("true") => Ok(true),
+                Ok("false") | Err(_) => Ok(false),
+                Ok(v) => Err(format!("MORIED_TRUST_PROXY must be either true or false, but got {:?}", v)),
+            };
+
+            let compression = match env::var("MORIED_COMPRESSION").as_deref() {
+                Ok("on") | Err(_) => Ok(true),
+                Ok("off") => Ok(false),
+                Ok(v) => Err(format!("MORIED_COMPRESSION must be either on or off, but got {:?}", v)),
+            };
+
+            let branch = match env::var("MORIED_BRANCH") {
+                Ok(branch) if git2::Branch::name_is_valid(&branch).unwrap_or(false) => Ok(Some(branch)),
+                Ok(branch) => Err(format!("MORIED_BRANCH must be a valid branch name, but got {:?}", branch)),
+                Err(_) => Ok(None),
+            };
+
+            let pull_interval = positive_integer_var("MORIED_PULL_INTERVAL")
+                .map(|secs| time::Duration::from_secs(secs.map_or(300, |secs| secs as u64)));
+
+            let trash_dir = match env::var("MORIED_TRASH").as_deref() {
+                Ok("true") => match env::var("MORIED_TRASH_DIR") {
+                    Ok(dir) if dir.trim_matches('/').is_empty() => Err("MORIED_TRASH_DIR must not be empty".to_owned()),
+                    Ok(dir) => Ok(Some(dir.trim_matches('/').to_owned())),
+                    Err(_) => Ok(Some(".trash".to_owned())),
+                },
+                Ok("false") | Err(_) => Ok(None),
+                Ok(v) => Err(format!("MORIED_TRASH must be either true or false, but got {:?}", v)),
+            };
+
+            let git_dir = collect_error(git_dir, &mut errors);
+            let listen = collect_error(listen, &mut errors);
+            let root_path = collect_error(root_path, &mut errors);
+            let cors_origins = collect_error(cors_origins, &mut errors);
+            let secret = collect_error(secret, &mut errors);
+            let token_ttl = collect_error(token_ttl, &mut errors);
+            let max_upload_bytes = collect_error(max_upload_bytes, &mut errors);
+            let max_import_bytes = collect_error(max_import_bytes, &mut errors);
+            let tls = collect_error(tls, &mut errors);
+            let metrics_enabled = collect_error(metrics_enabled, &mut errors);
+            let compression = collect_error(compression, &mut errors);
+            let login_max_attempts = collect_error(login_max_attempts, &mut errors);
+            let trust_proxy = collect_error(trust_proxy, &mut errors);
+            let branch = collect_error(branch, &mut errors);
+            let pull_interval = collect_error(pull_interval, &mut errors);
+            let trash_dir = collect_error(trash_dir, &mut errors);
+            let users = match Users::load() {
+                Ok(users) => Some(users),
+                Err(mut e) => {
+                    errors.append(&mut e);
+                    None
+                },
+            };
+            if !errors.is_empty() {
+                return Err(errors);
+            }
+
+            // Everything is available as no error has occurred
+            let secret = secret.unwrap();
+            Ok(Config {
+                git_dir: git_dir.unwrap(),
+                listen: listen.unwrap(),
+                root_path: root_path.unwrap(),
+                cors_origins: cors_origins.unwrap(),
+                encoding_key: jwt::EncodingKey::from_secret(secret.as_bytes()),
+                decoding_key: jwt::DecodingKey::from_secret(secret.as_bytes()),
+                token_ttl: token_ttl.unwrap(),
+                max_upload_bytes: max_upload_bytes.unwrap(),
+                max_import_bytes: max_import_bytes.unwrap(),
+                users: Mutex::new(users.unwrap()),
+                cache_file: env::var("MORIED_CACHE_FILE").map_or_else(|_| PathBuf::from("cache.msgpack"), PathBuf::from),
+                login_max_attempts: login_max_attempts.unwrap(),
+                trust_proxy: trust_proxy.unwrap(),
+                tls: tls.unwrap(),
+                metrics_enabled: metrics_enabled.unwrap(),
+                compression: compression.unwrap(),
+                branch: branch.unwrap(),
+                push_remote: env::var("MORIED_PUSH_REMOTE").ok(),
+                ssh_key: env::var("MORIED_SSH_KEY").ok().map(PathBuf::from),
+                push_token: env::var("MORIED_PUSH_TOKEN").ok(),
+                pull_remote: env::var("MORIED_PULL_REMOTE").ok(),
+                pull_interval: pull_interval.unwrap(),
+                note_extensions: env::var("MORIED_NOTE_EXTENSIONS").ok().map(|extensions| {
+                    extensions.split(',')
+                        .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+                        .filter(|ext| !ext.is_empty())
+                        .collect()
+                }),
+                trash_dir: trash_dir.unwrap(),
+            })
+        }
+    }
+
+    fn collect_error<T>(result: Result<T, String>, errors: &mut Vec<String>) -> Option<T> {
+        result.map_err(|e| errors.push(e)).ok()
+    }
+
+    fn required_var(name: &str) -> Result<String, String> {
+        env::var(name).map_err(|_| format!("{} must be set", name))
+    }
+
+    fn parse_origins<'a>(name: &str, origins: impl IntoIterator<Item = &'a str>) -> Result<Vec<HeaderValue>, String> {
+        origins.into_iter()
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| origin.parse::<HeaderValue>().map_err(|_| format!("{} contains an invalid origin: {:?}", name, origin)))
+            .collect()
+    }
+
+    /// Reads an optional environment variable which must be a positive integer if set.
+    fn positive_integer_var(name: &str) -> Result<Option<i64>, String> {
+        match env::var(name) {
+            Ok(v) => match v.parse::<i64>() {
+                Ok(n) if n > 0 => Ok(Some(n)),
+                _ => Err(format!("{} must be a positive integer, but got {:?}", name, v)),
+            },
+            Err(_) => Ok(None),
+        }
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct User {
+        pub email: String,
+        pub argon2_hash: String,
+    }
+
+    /// Accounts allowed to log in, keyed by user name.
+    #[derive(Debug, Clone)]
+    pub struct Users {
+        /// Path to the file the users are loaded from, if any.
+        pub file: Option<PathBuf>,
+        pub users: HashMap<String, User>,
+    }
+
+    impl Users {
+        /// Loads users from a JSON file, or a TOML file if its extension is
+        /// `.toml`, at `MORIED_USERS_FILE` if set, or the single user
+        /// configured by `MORIED_USER_NAME`, `MORIED_USER_EMAIL` and
+        /// `MORIED_USER_HASH` otherwise.
+        pub fn load() -> Result<Users, Vec<String>> {
+            match env::var("MORIED_USERS_FILE") {
+                Ok(path) => {
+                    let path = PathBuf::from(path);
+                    let text = fs::read_to_string(&path).map_err(|e| vec![format!("MORIED_USERS_FILE: failed to open {}: {}", path.display(), e)])?;
+                    let users = if is_toml(&path) {
+                        toml::from_str(&text).map_err(|e| e.to_string())
+                    }
+                    else {
+                        serde_json::from_str(&text).map_err(|e| e.to_string())
+                    };
+                    let users = users.map_err(|e| vec![format!("MORIED_USERS_FILE: failed to parse {}: {}", path.display(), e)])?;
+                    Ok(Users {
+                        file: Some(path),
+                        users,
+                    })
+                },
+                Err(_) => {
+                    let mut errors = Vec::new();
+                    let name = collect_error(required_var("MORIED_USER_NAME"), &mut errors);
+                    let email = collect_error(required_var("MORIED_USER_EMAIL"), &mut errors);
+                    let argon2_hash = collect_error(required_var("MORIED_USER_HASH"), &mut errors);
+                    match (name, email, argon2_hash) {
+                        (Some(name), Some(email), Some(argon2_hash)) => Ok(Users {
+                            file: None,
+                            users: HashMap::from([(name, User { email, argon2_hash })]),
+                        }),
+                        _ => Err(errors),
+                    }
+                },
+            }
+        }
+
+        /// Writes the users back to the file they are loaded from.
+        pub fn save(&self) -> io::Result<()> {
+            if let Some(path) = &self.file {
+                // Replace the file at once so that it is never left half-written
+                let tmp_path = path.with_extension("tmp");
+                let mut file = File::create(&tmp_path)?;
+                if is_toml(path) {
+                    let text = toml::to_string_pretty(&self.users).map_err(io::Error::other)?;
+                    file.write_all(text.as_bytes())?;
+                }
+                else {
+                    serde_json::to_writer_pretty(&mut file, &self.users)?;
+                }
+                file.sync_all()?;
+                fs::rename(&tmp_path, path)?;
+            }
+            Ok(())
+        }
+
+        pub fn get(&self, name: &str) -> Option<&User> {
+            self.users.get(name)
+        }
+    }
+
+    fn is_toml(path: &Path) -> bool {
+        path.extension().is_some_and(|ext| ext == "toml")
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct Login {
+        pub user: String,
+        pub password: String,
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct PasswordChange {
+        pub old_password: String,
+        pub new_password: String,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub enum NoteSave {
+        Save {
+            content: String,
+            /// Defaults to "Create <path>" or "Update <path>".
+            message: Option<String>,
+            base_commit: Option<String>,
+            #[serde(default)]
+            encoding: ContentEncoding,
+        },
+        Rename {
+            from: String,
+        },
+        /// Moves every file under the directory `from`.
+        RenameDir {
+            from: String,
+            /// Whether to replace files already at the destination.
+            #[serde(default)]
+            overwrite: bool,
+        },
+        Restore {
+            rev: String,
+        },
+    }
+
+    /// How the content of a note is given in JSON.
+    #[derive(Debug, Deserialize, Serialize, Clone, Copy, Default)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ContentEncoding {
+        /// As it is, which JSON strings can only be if it is valid UTF-8.
+        #[default]
+        Utf8,
+        /// Encoded in base64, for binary content.
+        Base64,
+    }
+
+    /// Reply to a write of a single file, telling the commit made for it.
+    #[derive(Debug, Serialize)]
+    pub struct Committed {
+        pub commit: String,
+        pub path: String,
+        /// Number of files moved, when moving a directory.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub moved: Option<usize>,
+    }
+
+    /// Sent to the subscribers of `/events` when a commit is made.
+    #[derive(Debug, Serialize, Clone)]
+    pub struct RepoEvent {
+        pub commit: String,
+        /// Files added, changed or removed by the commit.
+        pub paths: Vec<String>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct BatchCommitted {
+        pub commit: String,
+        pub paths: Vec<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct RevertNote {
+        pub commit_id: String,
+        pub message: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct BulkDelete {
+        pub paths: Vec<String>,
+        pub message: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct BulkDeleted {
+        /// The commit made, or `None` if none of the files existed.
+        pub commit: Option<String>,
+        pub deleted: Vec<String>,
+        pub not_found: Vec<String>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Imported {
+        pub commit: String,
+        pub paths: Vec<String>,
+        /// Names of the entries not imported as their paths would escape the repository.
+        pub skipped: Vec<String>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Uploaded {
+        pub commit: String,
+        pub files: Vec<UploadedFile>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct UploadedFile {
+        /// Name of the form field the file was sent as.
+        pub field: String,
+        pub path: String,
+        pub blob: String,
+    }
+
+    /// Result of a write together with the status of pushing it to the remote.
+    #[derive(Debug, Serialize)]
+    pub struct Pushed<T> {
+        #[serde(flatten)]
+        pub result: T,
+        /// `None` unless MORIED_PUSH_REMOTE is set.
+        pub push: Option<PushStatus>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct PushStatus {
+        pub remote: String,
+        pub ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub error: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct NewTag {
+        pub name: String,
+        pub message: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct GitTag {
+        pub name: String,
+        /// Object the tag points to, usually a commit.
+        pub target: String,
+        pub message: Option<String>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct CacheCleared {
+        /// The head commit, or `None` before the first commit.
+        pub head: Option<String>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Synced {
+        pub head: String,
+        /// Whether the branch has moved.
+        pub updated: bool,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Health {
+        pub status: &'static str,
+        /// The latest commit, or `None` before the first one.
+        pub head: Option<String>,
+    }
+
+    /// Notes saved together in a single commit.
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct NoteBatch {
+        pub message: String,
+        pub notes: Vec<NoteBatchItem>,
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    pub struct NoteBatchItem {
+        pub path: String,
+        pub content: String,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::ServiceExt;
+
+    /// A repository in a temporary directory, which is removed when dropped.
+    struct TempRepo {
+        dir: PathBuf,
+        repo: Repository,
+    }
+
+    impl TempRepo {
+        fn new() -> TempRepo {
+            let dir = std::env::temp_dir().join(format!("moried-test-{}", Uuid::new_v4()));
+            let repo = Repository::init(&dir).unwrap();
+            let mut config = repo.config().unwrap();
+            config.set_str("user.name", "Test").unwrap();
+            config.set_str("user.email", "test@example.com").unwrap();
+            TempRepo { dir, repo }
+        }
+
+        /// Commits the files on top of HEAD, if any.
+        fn commit(&self, files: &[(&str, &str)]) -> Oid {
+            let head_commit = self.repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+            let mut index = Index::new().unwrap();
+            if let Some(head_commit) = &head_commit {
+                index.read_tree(&head_commit.tree().unwrap()).unwrap();
+            }
+            for (path, content) in files {
+                index.add(&blob_entry(path, self.repo.blob(content.as_bytes()).unwrap(), 0o100644)).unwrap();
+            }
+            let tree = self.repo.find_tree(index.write_tree_to(&self.repo).unwrap()).unwrap();
+            let signature = self.repo.signature().unwrap();
+            self.repo.commit(Some("HEAD"), &signature, &signature, "test", &tree, &head_commit.iter().collect::<Vec<_>>()).unwrap()
+        }
+
+        /// Reads the file in HEAD.
+        fn read(&self, path: &str) -> Option<String> {
+            let tree = self.repo.head().ok()?.peel_to_tree().ok()?;
+            let blob = tree.get_path(std::path::Path::new(path)).ok()?.to_object(&self.repo).ok()?.peel_to_blob().ok()?;
+            Some(String::from_utf8_lossy(blob.content()).into_owned())
+        }
+    }
+
+    impl Drop for TempRepo {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.dir).ok();
+        }
+    }
+
+    /// Serves the repository with the defaults of the configuration.
+    /// The list of notes is cached in the repository's directory rather than the working one.
+    fn test_state(repo: &TempRepo) -> Arc<AppState> {
+        let config = Config {
+            git_dir: repo.dir.clone(),
+            listen: "127.0.0.1:0".to_owned(),
+            root_path: "/".to_owned(),
+            cors_origins: None,
+            encoding_key: jwt::EncodingKey::from_secret(b"secret"),
+            decoding_key: jwt::DecodingKey::from_secret(b"secret"),
+            token_ttl: chrono::Duration::days(1),
+            max_upload_bytes: 1024 * 1024,
+            max_import_bytes: 16 * 1024 * 1024,
+            users: tokio::sync::Mutex::new(Users { file: None, users: HashMap::new() }),
+            cache_file: repo.dir.join("cache.msgpack"),
+            login_max_attempts: 5,
+            trust_proxy: false,
+            tls: None,
+            metrics_enabled: false,
+            compression: true,
+            branch: None,
+            push_remote: None,
+            ssh_key: None,
+            push_token: None,
+            pull_remote: None,
+            pull_interval: time::Duration::from_secs(60),
+            note_extensions: None,
+            trash_dir: None,
+        };
+        Arc::new(AppState {
+            repo: Arc::new(SharedRepo::new(&repo.repo, None)),
+            cached_entries: Arc::new(tokio::sync::Mutex::new(Cached::None)),
+            cached_blob_ids: Arc::new(std::sync::Mutex::new(Cached::None)),
+            cached_last_modified: Arc::new(std::sync::Mutex::new(Cached::None)),
+            revoked_tokens: Default::default(),
+            push_lock: Default::default(),
+            login_attempts: Default::default(),
+            config: Arc::new(config),
+            metrics: None,
+            events: tokio::sync::broadcast::channel(EVENT_CAPACITY).0,
+            shutdown: Default::default(),
+        })
+    }
+
+    /// Changes the configuration of a state not shared yet.
+    fn configure(state: &mut Arc<AppState>, f: impl FnOnce(&mut Config)) {
+        f(Arc::get_mut(&mut Arc::get_mut(state).unwrap().config).unwrap());
+    }
+
+    /// Routes requests as `main` does.
+    fn test_app(state: &Arc<AppState>) -> Router {
+        router(Arc::clone(state))
+    }
+
+    /// Signs a token for the user `Test` with the secret of `test_state`.
+    fn test_token() -> String {
+        let claims = Claims {
+            sub: "Test".to_owned(),
+            exp: (Utc::now() + chrono::Duration::days(1)).timestamp() as usize,
+            email: "test@example.com".to_owned(),
+            jti: Uuid::new_v4().to_string(),
+        };
+        jwt::encode(&jwt::Header::default(), &claims, &jwt::EncodingKey::from_secret(b"secret")).unwrap()
+    }
+
+    /// Sends a request to the app as a logged-in user, telling the status and the body of the reply.
+    async fn send(app: &Router, method: Method, uri: &str, body: Option<serde_json::Value>) -> (StatusCode, Bytes) {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", test_token()));
+        let req = match body {
+            Some(body) => req.header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())),
+            None => req.body(Body::empty()),
+        };
+        let res = app.clone().oneshot(req.unwrap()).await.unwrap();
+        let status = res.status();
+        (status, axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap())
+    }
+
+    /// Lists the paths of the notes.
+    async fn list_paths(app: &Router) -> Vec<String> {
+        let (status, body) = send(app, Method::GET, "/notes", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let entries: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        let mut paths: Vec<String> = entries.iter().map(|entry| entry["path"].as_str().unwrap().to_owned()).collect();
+        paths.sort();
+        paths
+    }
+
+    fn save(content: &str) -> Option<serde_json::Value> {
+        Some(serde_json::json!({"Save": {"content": content, "message": "Save"}}))
+    }
+
+    /// Tells the status of a request for the path, as seen by a handler taking a `RepoPath`.
+    async fn repo_path_status(uri: &str) -> StatusCode {
+        let app: Router = Router::new().route("/notes/*path", get(|RepoPath(path): RepoPath| async move { path }));
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        app.oneshot(req).await.unwrap().status()
+    }
+
+    #[test]
+    fn validate_path_accepts_paths_inside_the_repository() {
+        for path in ["a.md", "dir/b.md", "deeply/nested/dir/c.md", "..hidden.md", "a..b.md"] {
+            assert!(validate_path(path).is_ok(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn validate_path_rejects_traversal() {
+        for path in ["../../etc/passwd", "..", "dir/../../etc/passwd", "dir/..", "./a.md", "dir/./a.md"] {
+            assert!(validate_path(path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn validate_path_rejects_absolute_and_empty_paths() {
+        for path in ["", "/", "/etc/passwd", "dir//a.md", "dir/"] {
+            assert!(validate_path(path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn repo_path_rejects_encoded_traversal() {
+        assert_eq!(repo_path_status("/notes/dir/a.md").await, StatusCode::OK);
+        assert_eq!(repo_path_status("/notes/%2e%2e%2f%2e%2e%2fetc%2fpasswd").await, StatusCode::BAD_REQUEST);
+        assert_eq!(repo_path_status("/notes/%2E%2E/a.md").await, StatusCode::BAD_REQUEST);
+        assert_eq!(repo_path_status("/notes/../../etc/passwd").await, StatusCode::BAD_REQUEST);
+        assert_eq!(repo_path_status("/notes/%2fetc%2fpasswd").await, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn parse_object_id_requires_full_ids() {
+        let id = "0123456789abcdef0123456789ABCDEF01234567";
+        assert_eq!(parse_object_id(id).unwrap().to_string(), id.to_ascii_lowercase());
+        for id in ["", "0123456", &id[1..], &format!("{}8", id), "g123456789abcdef0123456789abcdef01234567", "HEAD"] {
+            assert!(parse_object_id(id).is_err(), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn validate_path_rejects_nul() {
+        for path in ["a.md\0", "\0", "dir/\0/a.md", "a.md\0../../etc/passwd"] {
+            assert!(validate_path(path).is_err(), "{:?}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn repo_path_rejects_malicious_paths() {
+        for uri in [
+            "/notes/a.md%00",
+            "/notes/a.md%00.txt",
+            "/notes//etc/passwd",
+            "/notes/dir%2f..%2f..%2fetc%2fpasswd",
+            "/notes/dir/%2e%2e/%2e%2e/etc/passwd",
+            "/notes/%2e",
+        ] {
+            assert_eq!(repo_path_status(uri).await, StatusCode::BAD_REQUEST, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn front_matter_is_parsed_from_yaml_toml_and_json() {
+        let expected: Metadata = serde_yaml::from_str("title: Plan\ntags: [work, draft]\n").unwrap();
+        for text in [
+            "---\ntitle: Plan\ntags: [work, draft]\n---\n# Plan\n",
+            "+++\ntitle = \"Plan\"\ntags = [\"work\", \"draft\"]\n+++\n# Plan\n",
+            "{\n  \"title\": \"Plan\",\n  \"tags\": [\"work\", \"draft\"]\n}\n# Plan\n",
+            "---\r\ntitle: Plan\r\ntags: [work, draft]\r\n---\r\n# Plan\n",
+        ] {
+            let (metadata, body) = parse_front_matter(text).unwrap();
+            assert_eq!(metadata, expected, "{:?}", text);
+            assert_eq!(body, "# Plan\n", "{:?}", text);
+            assert_eq!(extract_metadata(text.as_bytes()), (Some(expected.clone()), Some("Plan".to_owned())), "{:?}", text);
+        }
+
+        // TOML dates are kept as strings
+        let (metadata, _) = parse_front_matter("+++\ndate = 2024-01-02\n+++\n").unwrap();
+        assert_eq!(metadata["date"], "2024-01-02");
+    }
+
+    #[test]
+    fn metadata_header_is_ascii_json() {
+        let header = metadata_header("a.md", "---\ntitle: 日記 😀\ntags: [a]\n---\nbody\n".as_bytes()).unwrap();
+        let header = header.to_str().unwrap();
+        assert!(header.is_ascii());
+        assert_eq!(serde_json::from_str::<serde_json::Value>(header).unwrap(), serde_json::json!({"title": "日記 😀", "tags": ["a"]}));
+
+        assert!(metadata_header("a.md", b"# No frontmatter\n").is_none());
+        assert!(metadata_header("a.txt", b"---\ntitle: a\n---\n").is_none());
+        let large = format!("---\ntitle: {}\n---\n", "a".repeat(10000));
+        assert!(metadata_header("a.md", large.as_bytes()).is_none());
+    }
+
+    #[test]
+    fn malformed_front_matter_is_reported() {
+        for text in ["---\ntitle: [\n---\nbody\n", "+++\ntitle = \n+++\nbody\n", "{\n\"title\": \n}\nbody\n"] {
+            let (metadata, body) = parse_front_matter(text).unwrap();
+            assert!(metadata["error"].is_string(), "{:?}", text);
+            assert_eq!(body, "body\n", "{:?}", text);
+        }
+        // Without the closing line, there is no front matter at all
+        for text in ["---\ntitle: Plan\n", "{braces} in prose\n", "# Plan\n---\n"] {
+            assert!(parse_front_matter(text).is_none(), "{:?}", text);
+        }
+    }
+
+    #[tokio::test]
+    async fn head_index_is_empty_before_the_first_commit() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let shared = state.repo.read().await.unwrap();
+        assert!(shared.is_unborn());
+        let (head_commit, index) = head_index(&shared).unwrap();
+        assert!(head_commit.is_none());
+        assert!(index.is_empty());
+    }
+
+    #[tokio::test]
+    async fn first_note_is_saved_to_an_empty_repository() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+
+        assert!(list_paths(&app).await.is_empty());
+        assert_eq!(send(&app, Method::GET, "/notes/first.md", None).await.0, StatusCode::NOT_FOUND);
+
+        let (status, _) = send(&app, Method::PUT, "/notes/first.md", save("# First\n")).await;
+        assert_eq!(status, StatusCode::OK);
+        let head_commit = repo.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head_commit.parent_count(), 0);
+        assert_eq!(repo.read("first.md").as_deref(), Some("# First\n"));
+
+        // The next save goes on top of the first commit
+        let (status, _) = send(&app, Method::PUT, "/notes/second.md", save("# Second\n")).await;
+        assert_eq!(status, StatusCode::OK);
+        let head_commit = repo.repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head_commit.parent_count(), 1);
+        assert_eq!(list_paths(&app).await, ["first.md", "second.md"]);
+    }
+
+    #[tokio::test]
+    async fn saves_without_a_message_are_titled_by_what_they_do() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        let head_message = || repo.repo.head().unwrap().peel_to_commit().unwrap().message().unwrap().to_owned();
+
+        let save = |content: &str| Some(serde_json::json!({"Save": {"content": content}}));
+        assert_eq!(send(&app, Method::PUT, "/notes/dir/a.md", save("a")).await.0, StatusCode::OK);
+        assert_eq!(head_message(), "Create dir/a.md");
+        assert_eq!(send(&app, Method::PUT, "/notes/dir/a.md", save("b")).await.0, StatusCode::OK);
+        assert_eq!(head_message(), "Update dir/a.md");
+
+        let save = Some(serde_json::json!({"Save": {"content": "c", "message": "Rewrite"}}));
+        assert_eq!(send(&app, Method::PUT, "/notes/dir/a.md", save).await.0, StatusCode::OK);
+        assert_eq!(head_message(), "Rewrite");
+    }
+
+    #[tokio::test]
+    async fn commits_are_sent_to_subscribers() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        let mut events = state.events.subscribe();
+
+        assert_eq!(send(&app, Method::PUT, "/notes/a.md", save("a")).await.0, StatusCode::OK);
+        let rename = Some(serde_json::json!({"Rename": {"from": "a.md"}}));
+        assert_eq!(send(&app, Method::PUT, "/notes/b.md", rename).await.0, StatusCode::OK);
+
+        let head = repo.repo.head().unwrap().peel_to_commit().unwrap();
+        let created = events.try_recv().unwrap();
+        assert_eq!(created.commit, head.parent_id(0).unwrap().to_string());
+        assert_eq!(created.paths, ["a.md"]);
+        let renamed = events.try_recv().unwrap();
+        assert_eq!(renamed.commit, head.id().to_string());
+        assert_eq!(renamed.paths, ["b.md", "a.md"]);
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn unpack_zip_skips_entries_escaping_the_root() {
+        use zip::write::SimpleFileOptions;
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (name, content) in [("a.md", "a"), ("../evil.md", "evil"), ("/etc/passwd", "root"), ("dir/nested/b.md", "b")] {
+            zip.start_file(name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.add_directory("empty/", SimpleFileOptions::default()).unwrap();
+        let data = zip.finish().unwrap().into_inner();
+
+        let (files, skipped) = unpack_zip(&data, 1024, 1024, 10).unwrap();
+        let files: Vec<(&str, &[u8])> = files.iter().map(|file| (file.path.as_str(), file.content.as_slice())).collect();
+        assert_eq!(files, [("a.md", b"a".as_slice()), ("dir/nested/b.md", b"b".as_slice())]);
+        assert_eq!(skipped, ["../evil.md", "/etc/passwd"]);
+
+        assert!(unpack_zip(&data, 0, 1024, 10).is_err());
+        assert!(unpack_zip(b"not a zip", 1024, 1024, 10).is_err());
+    }
+
+    #[test]
+    fn unpack_zip_limits_the_total_size_and_the_number_of_entries() {
+        use zip::write::SimpleFileOptions;
+
+        // Each file is well within the limit, but not all of them together
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for i in 0..100 {
+            zip.start_file(format!("dir/{}.md", i), SimpleFileOptions::default()).unwrap();
+            zip.write_all(&[b'a'; 100]).unwrap();
+        }
+        let data = zip.finish().unwrap().into_inner();
+
+        let (files, _) = unpack_zip(&data, 100, 100 * 100, 100).unwrap();
+        assert_eq!(files.len(), 100);
+        let e = unpack_zip(&data, 100, 100 * 100 - 1, 100).err().unwrap();
+        assert_eq!(e.code, StatusCode::PAYLOAD_TOO_LARGE.as_u16());
+        let e = unpack_zip(&data, 100, 100 * 100, 99).err().unwrap();
+        assert_eq!(e.code, StatusCode::PAYLOAD_TOO_LARGE.as_u16());
+    }
+
+    #[tokio::test]
+    async fn events_end_on_shutdown() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+
+        let req = Request::get("/events")
+            .header(header::AUTHORIZATION, format!("Bearer {}", test_token()))
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        state.shutdown.cancel();
+        let body = tokio::time::timeout(time::Duration::from_secs(5), axum::body::to_bytes(res.into_body(), usize::MAX)).await;
+        assert!(body.is_ok(), "the stream did not end");
+    }
+
+    #[tokio::test]
+    async fn binary_notes_are_saved_from_base64() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+
+        let save = |content: &str, encoding: &str| Some(serde_json::json!({"Save": {"content": content, "encoding": encoding}}));
+        assert_eq!(send(&app, Method::PUT, "/notes/a.bin", save("AP+A/w==", "base64")).await.0, StatusCode::OK);
+        let (_, body) = send(&app, Method::GET, "/notes/a.bin", None).await;
+        assert_eq!(body, [0x00, 0xff, 0x80, 0xff].as_slice());
+
+        assert_eq!(send(&app, Method::PUT, "/notes/a.bin", save("not base64!", "base64")).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(send(&app, Method::PUT, "/notes/a.bin", save("AP+A/w==", "utf16")).await.0, StatusCode::UNPROCESSABLE_ENTITY);
+
+        // Text which is not valid UTF-8 cannot even be parsed
+        let req = Request::put("/notes/b.md")
+            .header(header::AUTHORIZATION, format!("Bearer {}", test_token()))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(&b"{\"Save\": {\"content\": \"\xff\"}}"[..]))
+            .unwrap();
+        assert!(app.clone().oneshot(req).await.unwrap().status().is_client_error());
+        assert_eq!(list_paths(&app).await, ["a.bin"]);
+    }
+
+    #[tokio::test]
+    async fn nested_notes_are_listed_and_loaded() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[]);
+
+        let paths = ["top.md", "projects/plan.md", "projects/2024/q1/plan.md", "a/b/c/d/e/f/g/deep.md"];
+        for path in paths {
+            let (status, _) = send(&app, Method::PUT, &format!("/notes/{}", path), save(&format!("# {}\n", path))).await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+        }
+
+        let mut expected = paths.to_vec();
+        expected.sort();
+        assert_eq!(list_paths(&app).await, expected);
+        for path in paths {
+            let (status, body) = send(&app, Method::GET, &format!("/notes/{}", path), None).await;
+            assert_eq!(status, StatusCode::OK, "{}", path);
+            assert_eq!(body, format!("# {}\n", path).as_bytes(), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn nested_notes_do_not_replace_files_or_directories() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[]);
+        assert_eq!(send(&app, Method::PUT, "/notes/a/b.md", save("b")).await.0, StatusCode::OK);
+
+        // A file cannot become a directory, nor a directory a file
+        assert_eq!(send(&app, Method::PUT, "/notes/a/b.md/c.md", save("c")).await.0, StatusCode::CONFLICT);
+        assert_eq!(send(&app, Method::PUT, "/notes/a", save("a")).await.0, StatusCode::CONFLICT);
+        // Empty segments are refused
+        assert_eq!(send(&app, Method::PUT, "/notes/a//c.md", save("c")).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(send(&app, Method::PUT, "/notes/a/c/", save("c")).await.0, StatusCode::BAD_REQUEST);
+
+        assert_eq!(list_paths(&app).await, ["a/b.md"]);
+        assert_eq!(repo.read("a/b.md").as_deref(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn list_is_updated_incrementally_after_a_save() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[]);
+        assert_eq!(send(&app, Method::PUT, "/notes/untouched.md", save("untouched")).await.0, StatusCode::OK);
+        assert_eq!(send(&app, Method::PUT, "/notes/updated.md", save("old")).await.0, StatusCode::OK);
+        assert_eq!(list_paths(&app).await, ["untouched.md", "updated.md"]);
+
+        // Mark the cached entry of a note left alone, which a full rebuild would reset
+        if let Cached::Computed { data, .. } = &mut *state.cached_entries.lock().await {
+            data.iter_mut().find(|entry| entry.path == std::path::Path::new("untouched.md")).unwrap().size = 12345;
+        }
+
+        assert_eq!(send(&app, Method::PUT, "/notes/updated.md", save("new content")).await.0, StatusCode::OK);
+        assert_eq!(send(&app, Method::PUT, "/notes/created.md", save("created")).await.0, StatusCode::OK);
+        {
+            let shared = state.repo.read().await.unwrap();
+            assert!(matches!(state.cached_entries.lock().await.get(&shared), Cache::Invalid(..)));
+        }
+
+        let (_, body) = send(&app, Method::GET, "/notes", None).await;
+        let entries: Vec<ListEntry> = serde_json::from_slice(&body).unwrap();
+        let size_of = |path: &str| entries.iter().find(|entry| entry.path == std::path::Path::new(path)).map(|entry| entry.size);
+        assert_eq!(size_of("untouched.md"), Some(12345));
+        assert_eq!(size_of("updated.md"), Some("new content".len()));
+        assert_eq!(size_of("created.md"), Some("created".len()));
+        assert_eq!(entries.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn list_is_rebuilt_from_a_cache_of_another_history() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[]);
+        repo.commit(&[("a.md", "a")]);
+
+        // As if the cache file had been written for another repository
+        *state.cached_entries.lock().await = Cached::Computed {
+            commit_id: Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap(),
+            data: Vec::new(),
+        };
+        assert_eq!(list_paths(&app).await, ["a.md"]);
+        assert!(repo.dir.join("cache.msgpack").exists());
+    }
+
+    #[test]
+    fn find_blob_id_looks_up_nested_files_only() {
+        let repo = TempRepo::new();
+        let commit_id = repo.commit(&[("a.md", "b"), ("dir/b.md", "b"), ("dir/sub/c.md", "b")]);
+        let tree = repo.repo.find_commit(commit_id).unwrap().tree().unwrap();
+        let blob_id = repo.repo.blob(b"b").unwrap();
+
+        assert_eq!(find_blob_id(&tree, "dir/b.md"), Some(blob_id));
+        assert_eq!(find_blob_id(&tree, "dir/sub/c.md"), Some(blob_id));
+        for path in ["dir", "dir/sub", "b.md", "dir/a.md", "a.md/b.md"] {
+            assert_eq!(find_blob_id(&tree, path), None, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejected_requests_reply_json_errors() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+
+        for (method, uri, body, status) in [
+            (Method::PUT, "/notes/x.md", Some(serde_json::json!({"Bogus": 1})), StatusCode::UNPROCESSABLE_ENTITY),
+            (Method::GET, "/notes?limit=abc", None, StatusCode::BAD_REQUEST),
+        ] {
+            let (actual, body) = send(&app, method, uri, body).await;
+            assert_eq!(actual, status, "{}", uri);
+            let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(error["code"], status.as_u16(), "{}", uri);
+            assert!(error["kind"].is_string() && error["message"].is_string(), "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn wrong_methods_are_not_allowed_before_authentication() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let deny = middleware::from_fn(|_: Request<Body>, _: Next| async { ApiError::unauthorized("denied").into_response() });
+        let app = Router::new()
+            .route("/notes", get(get_notes).route_layer(deny))
+            .with_state(Arc::clone(&state))
+            .layer(middleware::from_fn(method_not_allowed));
+
+        let req = Request::delete("/notes").body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(res.headers()[header::ALLOW], "GET,HEAD");
+        let error: serde_json::Value = serde_json::from_slice(&axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+        assert_eq!(error["kind"], "method_not_allowed");
+
+        assert_eq!(send(&app, Method::GET, "/notes", None).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn last_modified_times_outlive_commits_not_touching_them() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let first = repo.commit(&[("a.md", "a"), ("b.md", "b")]);
+        let cached_paths = || match &*state.cached_last_modified.lock().unwrap() {
+            Cached::Computed { commit_id, data } => {
+                let mut paths: Vec<String> = data.keys().cloned().collect();
+                paths.sort();
+                Some((*commit_id, paths))
+            },
+            Cached::None => None,
+        };
+
+        let shared = state.repo.read().await.unwrap();
+        assert!(last_modified(&state, &shared, "a.md").unwrap().is_some());
+        assert!(last_modified(&state, &shared, "b.md").unwrap().is_some());
+        assert!(last_modified(&state, &shared, "none.md").unwrap().is_none());
+        assert_eq!(cached_paths(), Some((first, vec!["a.md".to_owned(), "b.md".to_owned()])));
+
+        // Only the time of the file changed is dropped
+        let second = repo.commit(&[("b.md", "b2"), ("c.md", "c")]);
+        assert!(last_modified(&state, &shared, "c.md").unwrap().is_some());
+        assert_eq!(cached_paths(), Some((second, vec!["a.md".to_owned(), "c.md".to_owned()])));
+    }
+
+    #[tokio::test]
+    async fn log_is_paged_newest_first() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        let log_ids = |body: Bytes| -> Vec<String> {
+            let log: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+            log.iter().map(|entry| entry["id"].as_str().unwrap().to_owned()).collect()
+        };
+
+        let (status, body) = send(&app, Method::GET, "/log", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(log_ids(body).is_empty());
+
+        let ids: Vec<String> = (0..3).map(|i| repo.commit(&[("a.md", &i.to_string())]).to_string()).collect();
+        assert_eq!(log_ids(send(&app, Method::GET, "/log", None).await.1), [ids[2].as_str(), &ids[1], &ids[0]]);
+        assert_eq!(log_ids(send(&app, Method::GET, "/log?offset=1&limit=1", None).await.1), [ids[1].as_str()]);
+        assert!(log_ids(send(&app, Method::GET, "/log?offset=3", None).await.1).is_empty());
+    }
+
+    #[tokio::test]
+    async fn note_metadata_is_loaded_without_the_body() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[("a.md", "---\ntags: [work]\n---\n# A\n"), ("b.md", "# B\n")]);
+
+        let (status, body) = send(&app, Method::GET, "/notes/a.md/meta", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), serde_json::json!({"tags": ["work"]}));
+        let (status, body) = send(&app, Method::GET, "/notes/b.md/meta", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), serde_json::json!({}));
+        assert_eq!(send(&app, Method::GET, "/notes/none.md/meta", None).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn note_tags_are_counted_from_lists_and_strings() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[
+            ("a.md", "---\ntags: [work, draft]\n---\n"),
+            ("b.md", "---\ntags: work\n---\n"),
+            ("c.md", "---\ntags: 42\n---\n"),
+            ("d.md", "# No tags\n"),
+        ]);
+
+        let (status, body) = send(&app, Method::GET, "/tags/notes", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), serde_json::json!({"draft": 1, "work": 2}));
+    }
+
+    #[tokio::test]
+    async fn notes_are_blamed_line_by_line() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        let first = repo.commit(&[("a.md", "one\ntwo\n")]);
+        let second = repo.commit(&[("a.md", "one\n2\nthree\n"), ("b.bin", "\0\0\0")]);
+
+        let (status, body) = send(&app, Method::GET, "/notes/a.md/blame", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let lines: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        let commit_ids: Vec<&str> = lines.iter().map(|line| line["commit_id"].as_str().unwrap()).collect();
+        assert_eq!(commit_ids, [first.to_string(), second.to_string(), second.to_string()]);
+
+        assert_eq!(send(&app, Method::GET, "/notes/none.md/blame", None).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(send(&app, Method::GET, "/notes/b.bin/blame", None).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn notes_are_diffed_between_commits() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        let first = repo.commit(&[("a.md", "one\n")]);
+        repo.commit(&[("a.md", "one\ntwo\n")]);
+
+        let (status, body) = send(&app, Method::GET, &format!("/notes/a.md/diff?from={}", first), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let patch = String::from_utf8(body.to_vec()).unwrap();
+        assert!(patch.contains("\n+two\n"), "{}", patch);
+
+        assert_eq!(send(&app, Method::GET, "/notes/a.md/diff", None).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(send(&app, Method::GET, "/notes/a.md/diff?from=xyz", None).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn notes_are_diffed_alike_by_either_route() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        let first = repo.commit(&[("a.md", "one\n")]);
+        let second = repo.commit(&[("a.md", "one\ntwo\n"), ("b.md", "b")]);
+
+        let (status, by_range) = send(&app, Method::GET, &format!("/notes/a.md?diff={}..{}", first, second), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let (_, by_suffix) = send(&app, Method::GET, &format!("/notes/a.md/diff?from={}&to={}", first, second), None).await;
+        assert_eq!(by_range, by_suffix);
+
+        // Either side may be missing
+        let (status, body) = send(&app, Method::GET, &format!("/notes/b.md?diff={}..{}", first, second), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(error["message"].as_str().unwrap().starts_with("old side:"), "{}", error);
+        let missing = "0".repeat(40);
+        let (status, body) = send(&app, Method::GET, &format!("/notes/a.md?diff={}..{}", first, missing), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(error["message"].as_str().unwrap().starts_with("new side:"), "{}", error);
+        assert_eq!(send(&app, Method::GET, &format!("/notes/a.md?diff={}", first), None).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn writes_reply_alike_with_or_without_a_remote() {
+        let repo = TempRepo::new();
+        let remote = TempRepo::new();
+        let bare = Repository::init_bare(remote.dir.join("bare.git")).unwrap();
+        repo.repo.remote("origin", bare.path().to_str().unwrap()).unwrap();
+        repo.commit(&[("README.md", "readme")]);
+
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        let (status, body) = send(&app, Method::PUT, "/notes/a.md", save("a")).await;
+        assert_eq!(status, StatusCode::OK);
+        let reply: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(reply, serde_json::json!({"commit": reply["commit"], "path": "a.md", "push": null}));
+
+        let mut state = test_state(&repo);
+        configure(&mut state, |config| config.push_remote = Some("origin".to_owned()));
+        let app = test_app(&state);
+        let (status, body) = send(&app, Method::PUT, "/notes/b.md", save("b")).await;
+        assert_eq!(status, StatusCode::OK);
+        let reply: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(reply, serde_json::json!({"commit": reply["commit"], "path": "b.md", "push": {"remote": "origin", "ok": true}}));
+        let branch = repo.repo.head().unwrap().name().unwrap().to_owned();
+        assert_eq!(bare.refname_to_id(&branch).unwrap().to_string(), reply["commit"]);
+    }
+
+    #[tokio::test]
+    async fn head_replies_the_headers_of_get() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[("a.md", "---\ntitle: A\n---\nbody\n")]);
+
+        let request = |method: Method, headers: &[(HeaderName, &str)]| {
+            let mut req = Request::builder()
+                .method(method)
+                .uri("/notes/a.md")
+                .header(header::AUTHORIZATION, format!("Bearer {}", test_token()));
+            for (name, value) in headers {
+                req = req.header(name, *value);
+            }
+            app.clone().oneshot(req.body(Body::empty()).unwrap())
+        };
+        let get = request(Method::GET, &[]).await.unwrap();
+        let head = request(Method::HEAD, &[]).await.unwrap();
+        assert_eq!(head.status(), StatusCode::OK);
+        for name in [header::ETAG, header::LAST_MODIFIED, header::VARY, header::CONTENT_TYPE, header::CONTENT_LENGTH, HeaderName::from_static("x-note-metadata")] {
+            assert_eq!(head.headers().get(&name), get.headers().get(&name), "{}", name);
+        }
+
+        let modified = get.headers()[header::LAST_MODIFIED].to_str().unwrap().to_owned();
+        for method in [Method::GET, Method::HEAD] {
+            let res = request(method.clone(), &[(header::IF_MODIFIED_SINCE, &modified)]).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED, "{}", method);
+            assert_eq!(res.headers()[header::LAST_MODIFIED], modified.as_str());
+        }
+    }
+
+    #[tokio::test]
+    async fn feed_entries_have_content() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[("a.md", "a")]);
+        repo.commit(&[("b.md", "b")]);
+
+        let (status, body) = send(&app, Method::GET, "/feed?limit=1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let feed = std::str::from_utf8(&body).unwrap();
+        assert_eq!(feed.matches("<entry>").count(), 1);
+        assert!(feed.contains("<content type=\"text\">test</content>"), "{}", feed);
+        assert!(feed.contains("notes/b.md"), "{}", feed);
+    }
+
+    #[tokio::test]
+    async fn search_results_are_limited() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        let text = "match\n".repeat(MAX_LIMIT + 1);
+        repo.commit(&[("a.md", &text)]);
+
+        let hits = |body: axum::body::Bytes| serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap().len();
+        assert_eq!(hits(send(&app, Method::GET, "/search?q=match", None).await.1), 50);
+        assert_eq!(hits(send(&app, Method::GET, "/search?q=match&limit=2", None).await.1), 2);
+        assert_eq!(hits(send(&app, Method::GET, "/search?q=match&limit=100000", None).await.1), MAX_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn caches_are_cleared_before_the_first_commit() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+
+        let (status, body) = send(&app, Method::POST, "/admin/cache/clear", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), serde_json::json!({"head": null}));
+
+        let head = repo.commit(&[("a.md", "a")]);
+        let (_, body) = send(&app, Method::POST, "/admin/cache/clear", None).await;
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), serde_json::json!({"head": head.to_string()}));
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_rejected_as_json() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[("a.md", "a")]);
+
+        let requests = [
+            (Method::POST, "/tags", Some(serde_json::json!({"name": "v1"}))),
+            (Method::POST, "/notes-bulk-delete", Some(serde_json::json!({"paths": "a.md", "message": "m"}))),
+            (Method::GET, "/export?rev=a&rev=b", None),
+        ];
+        for (method, uri, body) in requests {
+            let (status, body) = send(&app, method, uri, body).await;
+            assert!(status.is_client_error(), "{} {}", uri, status);
+            let error: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert!(error["message"].is_string(), "{} {}", uri, error);
+        }
+    }
+
+    #[tokio::test]
+    async fn git_tags_are_listed_whatever_they_point_to() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        let commit_id = repo.commit(&[("a.md", "a")]);
+        let tree = repo.repo.find_commit(commit_id).unwrap().tree().unwrap();
+        repo.repo.tag_lightweight("tree", tree.as_object(), false).unwrap();
+
+        let tag = serde_json::json!({"name": "v1", "message": "First"});
+        assert_eq!(send(&app, Method::POST, "/tags", Some(tag)).await.0, StatusCode::CREATED);
+        let (status, body) = send(&app, Method::GET, "/tags", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap(), serde_json::json!([
+            {"name": "tree", "target": tree.id().to_string(), "message": null},
+            {"name": "v1", "target": commit_id.to_string(), "message": "First"},
+        ]));
+    }
+
+    #[tokio::test]
+    async fn bulk_deletes_delete_each_path_once() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[("a.md", "a"), ("b.md", "b")]);
+
+        let bulk = serde_json::json!({"paths": ["a.md", "c.md", "a.md"], "message": "Clean up"});
+        let (status, body) = send(&app, Method::POST, "/notes-bulk-delete", Some(bulk)).await;
+        assert_eq!(status, StatusCode::OK);
+        let reply: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(reply["deleted"], serde_json::json!(["a.md"]));
+        assert_eq!(reply["not_found"], serde_json::json!(["c.md"]));
+        assert_eq!(repo.read("a.md"), None);
+        assert_eq!(repo.read("b.md").as_deref(), Some("b"));
+
+        let bulk = serde_json::json!({"paths": ["a.md"], "message": "Clean up"});
+        let (status, body) = send(&app, Method::POST, "/notes-bulk-delete", Some(bulk)).await;
+        assert_eq!(status, StatusCode::OK);
+        let reply: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(reply, serde_json::json!({"commit": null, "deleted": [], "not_found": ["a.md"], "push": null}));
+    }
+
+    #[tokio::test]
+    async fn bulk_deletes_go_to_the_trash() {
+        let repo = TempRepo::new();
+        let mut state = test_state(&repo);
+        configure(&mut state, |config| config.trash_dir = Some(".trash".to_owned()));
+        let app = test_app(&state);
+        repo.commit(&[("a.md", "a"), ("b.md", "b"), (".trash/c.md", "c")]);
+
+        let bulk = serde_json::json!({"paths": ["a.md", ".trash/c.md", "d.md", "a.md"], "message": "Clean up"});
+        let (status, body) = send(&app, Method::POST, "/notes-bulk-delete", Some(bulk)).await;
+        assert_eq!(status, StatusCode::OK);
+        let reply: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(reply["deleted"], serde_json::json!(["a.md", ".trash/c.md"]));
+        assert_eq!(reply["not_found"], serde_json::json!(["d.md"]));
+
+        assert_eq!(repo.read("a.md"), None);
+        assert_eq!(repo.read(".trash/a.md").as_deref(), Some("a"));
+        assert_eq!(repo.read(".trash/c.md"), None);
+        assert_eq!(repo.read("b.md").as_deref(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn api_is_routed_as_served() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[("dir/a.md", "a")]);
+
+        // Only the health check is public
+        let status = |uri: &str| {
+            let app = app.clone();
+            let req = Request::get(uri).body(Body::empty()).unwrap();
+            async move { app.oneshot(req).await.unwrap().status() }
+        };
+        assert_eq!(status("/health").await, StatusCode::OK);
+        for uri in ["/notes", "/notes/dir/a.md", "/tree/", "/log", "/events"] {
+            assert_eq!(status(uri).await, StatusCode::UNAUTHORIZED, "{}", uri);
+        }
+
+        for uri in ["/notes", "/notes/dir/a.md", "/tree/", "/tree/dir", "/files", "/log", "/tags", "/tags/notes"] {
+            assert_eq!(send(&app, Method::GET, uri, None).await.0, StatusCode::OK, "{}", uri);
+        }
+        assert_eq!(send(&app, Method::GET, "/nowhere", None).await.0, StatusCode::NOT_FOUND);
+        // Without a remote to sync with
+        assert_eq!(send(&app, Method::POST, "/sync", None).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn exports_are_made_at_a_commit() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        let first = repo.commit(&[("a.md", "a")]);
+        repo.commit(&[("b.md", "b")]);
+
+        let (status, body) = send(&app, Method::GET, &format!("/export?rev={}", first), None).await;
+        assert_eq!(status, StatusCode::OK);
+        let archive = zip::ZipArchive::new(std::io::Cursor::new(body)).unwrap();
+        assert_eq!(archive.file_names().collect::<Vec<_>>(), ["a.md"]);
+
+        assert_eq!(send(&app, Method::GET, "/export?rev=HEAD~1", None).await.0, StatusCode::BAD_REQUEST);
+        let missing = "0".repeat(40);
+        assert_eq!(send(&app, Method::GET, &format!("/export?rev={}", missing), None).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn trees_list_blobs_and_trees() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[("a.md", "a"), ("dir/b.md", "b")]);
+
+        let (status, body) = send(&app, Method::GET, "/tree/", None).await;
+        assert_eq!(status, StatusCode::OK);
+        let children: Vec<serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        let kinds: Vec<_> = children.iter().map(|child| (child["name"].as_str().unwrap(), child["kind"].as_str().unwrap())).collect();
+        assert_eq!(kinds, [("a.md", "blob"), ("dir", "tree")]);
+    }
+
+    #[tokio::test]
+    async fn histories_are_limited() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        for i in 0..=MAX_LIMIT {
+            repo.commit(&[("a.md", &i.to_string())]);
+        }
+
+        let entries = |body: Bytes| serde_json::from_slice::<Vec<serde_json::Value>>(&body).unwrap().len();
+        assert_eq!(entries(send(&app, Method::GET, "/history/a.md", None).await.1), 50);
+        assert_eq!(entries(send(&app, Method::GET, "/history/a.md?limit=2", None).await.1), 2);
+        assert_eq!(entries(send(&app, Method::GET, "/history/a.md?limit=100000", None).await.1), MAX_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn notes_have_the_same_weak_etag_compressed_or_not() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+        repo.commit(&[("a.md", &"a".repeat(4096))]);
+
+        let request = |method: Method, headers: &[(HeaderName, &str)]| {
+            let mut req = Request::builder()
+                .method(method)
+                .uri("/notes/a.md")
+                .header(header::AUTHORIZATION, format!("Bearer {}", test_token()));
+            for (name, value) in headers {
+                req = req.header(name, *value);
+            }
+            app.clone().oneshot(req.body(Body::empty()).unwrap())
+        };
+        let plain = request(Method::GET, &[]).await.unwrap();
+        let compressed = request(Method::GET, &[(header::ACCEPT_ENCODING, "gzip")]).await.unwrap();
+        assert_eq!(compressed.headers()[header::CONTENT_ENCODING], "gzip");
+        let etag = plain.headers()[header::ETAG].to_str().unwrap().to_owned();
+        assert!(etag.starts_with("W/"), "{}", etag);
+        assert_eq!(compressed.headers()[header::ETAG], etag.as_str());
+        assert_eq!(request(Method::HEAD, &[]).await.unwrap().headers()[header::ETAG], etag.as_str());
+
+        let revalidated = request(Method::GET, &[(header::ACCEPT_ENCODING, "gzip"), (header::IF_NONE_MATCH, &etag)]).await.unwrap();
+        assert_eq!(revalidated.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn login_attempts_are_limited_per_client() {
+        let repo = TempRepo::new();
+        let state = test_state(&repo);
+        let app = test_app(&state);
+
+        let login = |ip: [u8; 4]| {
+            let mut req = Request::builder()
+                .method(Method::POST)
+                .uri("/login")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"user": "nobody", "password": "wrong"}"#))
+                .unwrap();
+            req.extensions_mut().insert(ConnectInfo(SocketAddr::from((ip, 1234))));
+            app.clone().oneshot(req)
+        };
+        for _ in 0..5 {
+            assert_eq!(login([10, 0, 0, 1]).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(login([10, 0, 0, 1]).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+        // Others are not held up by the one client
+        assert_eq!(login([10, 0, 0, 2]).await.unwrap().status(), StatusCode::UNAUTHORIZED);
     }
 }